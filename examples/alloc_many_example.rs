@@ -0,0 +1,39 @@
+extern crate boehm = "boehm-rs";
+extern crate time;
+
+use boehm::Gc;
+
+static COUNT: uint = 10_000_000;
+
+// a typical small parser node.
+struct Node {
+    kind: uint,
+    left: Option<Gc<Node>>,
+    right: Option<Gc<Node>>,
+}
+
+fn leaf(i: uint) -> Node {
+    Node { kind: i, left: None, right: None }
+}
+
+#[start]
+fn main(_: int, _: *const *const u8) -> int {
+    boehm::init();
+
+    let start = time::precise_time_ns();
+    for i in range(0u, COUNT) {
+        Gc::new(leaf(i));
+    }
+    let individual = time::precise_time_ns() - start;
+
+    let start = time::precise_time_ns();
+    let mut list = boehm::alloc_many::<Node>();
+    for i in range(0u, COUNT) {
+        list.alloc(leaf(i));
+    }
+    let batched = time::precise_time_ns() - start;
+
+    println!("Gc::new:           {} ms", individual / 1_000_000);
+    println!("GcFreeList::alloc: {} ms", batched / 1_000_000);
+    0
+}
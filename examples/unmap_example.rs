@@ -0,0 +1,42 @@
+extern crate boehm = "boehm-rs";
+
+use std::io::File;
+use boehm::Gc;
+
+// the resident set size of this process, in bytes.
+fn rss() -> uint {
+    let statm = File::open(&Path::new("/proc/self/statm")).read_to_string().unwrap();
+    let pages: uint = from_str(statm.as_slice().words().nth(1).unwrap()).unwrap();
+    pages * 4096
+}
+
+fn report(when: &str) {
+    println!("{}: heap = {}, free = {}, unmapped = {}, rss = {}",
+             when, boehm::heap_size(), boehm::free_bytes(),
+             boehm::unmapped_bytes(), rss());
+}
+
+#[start]
+fn main(_: int, _: *const *const u8) -> int {
+    boehm::init();
+    report("start");
+
+    // a burst of allocation, all of which is garbage as soon as the
+    // loop finishes.
+    for i in range(0u, 1_000_000) {
+        Gc::new([i, .. 16]);
+    }
+    report("after burst");
+
+    // a normal collection frees the memory for reuse, but keeps it
+    // mapped...
+    boehm::collect();
+    report("after collect");
+
+    // ... while this hands it back to the OS, so the RSS should drop
+    // (if the GC was built with USE_MUNMAP).
+    boehm::collect_and_unmap();
+    report("after collect_and_unmap");
+
+    0
+}
@@ -0,0 +1,197 @@
+//! Capturing the output that Boehm writes directly to stdout/stderr.
+
+use libc;
+use std::io::{File, TempDir};
+use std::os;
+use std::rt::mutex::{StaticNativeMutex, NATIVE_MUTEX_INIT};
+
+use ffi;
+
+/// Serialises captures: two at once would each redirect the other's
+/// output, and could restore the descriptors in the wrong order.
+static mut CAPTURE_LOCK: StaticNativeMutex = NATIVE_MUTEX_INIT;
+
+/// Points `fd` back at `saved` (and closes `saved`) when dropped, so
+/// that the redirection is undone even if the captured code fails.
+struct Redirection {
+    fd: libc::c_int,
+    saved: libc::c_int,
+}
+
+impl Drop for Redirection {
+    fn drop(&mut self) {
+        unsafe {
+            libc::dup2(self.saved, self.fd);
+            libc::close(self.saved);
+        }
+    }
+}
+
+/// Run `f` with the file descriptor `fd` (e.g. `libc::STDERR_FILENO`)
+/// redirected to a temporary file, and return everything written to
+/// it.
+///
+/// Boehm writes its diagnostics with raw `write` calls, so this
+/// catches them, but it will also catch anything else written by
+/// other threads in the meantime. Only one capture runs at a time.
+pub fn capture_fd(fd: libc::c_int, f: ||) -> String {
+    let dir = TempDir::new("boehm-rs").unwrap();
+    let path = dir.path().join("output");
+
+    unsafe {
+        let _guard = CAPTURE_LOCK.lock();
+
+        let tmp = path.with_c_str(|p| {
+            libc::open(p, libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC,
+                       libc::S_IRUSR | libc::S_IWUSR)
+        });
+        if tmp < 0 {
+            fail!("could not open {} to capture output: {}", path.display(), os::last_os_error())
+        }
+
+        let saved = libc::dup(fd);
+        if saved < 0 {
+            libc::close(tmp);
+            fail!("could not duplicate fd {} to capture it: {}", fd, os::last_os_error())
+        }
+        if libc::dup2(tmp, fd) < 0 {
+            libc::close(tmp);
+            libc::close(saved);
+            fail!("could not redirect fd {}: {}", fd, os::last_os_error())
+        }
+        libc::close(tmp);
+
+        let _redirection = Redirection { fd: fd, saved: saved };
+        f();
+    }
+
+    File::open(&path).read_to_string().unwrap()
+}
+
+/// Run `f` in a forked copy of the process, and return everything it
+/// writes to stdout.
+///
+/// Unlike `capture_fd`, this leaves this process's file descriptors
+/// alone, so output from other threads isn't affected. The collector's
+/// lock is held over the fork (with `GC_atfork_prepare`), so the
+/// child sees a consistent heap.
+///
+/// This is unsafe because only the calling thread exists in the
+/// child, so `f` mustn't take any locks (other than the collector's)
+/// that other threads might have held at the time, nor use the Rust
+/// runtime (e.g. by printing or failing).
+///
+/// Fails if the child doesn't exit with status 0.
+pub unsafe fn capture_stdout_in_child(f: ||) -> String {
+    let mut fds = [0 as libc::c_int, .. 2];
+    if libc::pipe(fds.as_mut_ptr()) < 0 {
+        fail!("could not create a pipe to capture output: {}", os::last_os_error())
+    }
+    let (read_end, write_end) = (fds[0], fds[1]);
+
+    ffi::GC_atfork_prepare();
+    let pid = libc::fork();
+    if pid == 0 {
+        ffi::GC_atfork_child();
+        libc::close(read_end);
+        libc::dup2(write_end, libc::STDOUT_FILENO);
+        libc::close(write_end);
+        f();
+        libc::_exit(0);
+    }
+    let error = if pid < 0 { Some(os::last_os_error()) } else { None };
+    ffi::GC_atfork_parent();
+    libc::close(write_end);
+    match error {
+        Some(e) => {
+            libc::close(read_end);
+            fail!("could not fork to capture output: {}", e)
+        }
+        None => {}
+    }
+
+    // the child's output is read as it's written, since it may not
+    // fit in the pipe's buffer.
+    let mut output = Vec::new();
+    let mut buf = [0u8, .. 4096];
+    loop {
+        let n = libc::read(read_end, buf.as_mut_ptr() as *mut libc::c_void,
+                           buf.len() as libc::size_t);
+        if n > 0 {
+            output.push_all(buf.slice_to(n as uint));
+        } else if n == 0 || os::errno() != libc::EINTR as int {
+            break
+        }
+    }
+    libc::close(read_end);
+
+    let mut status = 0;
+    while libc::waitpid(pid, &mut status, 0) < 0 {
+        if os::errno() != libc::EINTR as int {
+            fail!("could not wait for the capturing child: {}", os::last_os_error())
+        }
+    }
+    // the output of a child that crashed or exited early is likely to
+    // be truncated, so it's not returned.
+    if status != 0 {
+        fail!("the capturing child didn't exit cleanly (wait status {:#x})", status)
+    }
+    String::from_utf8_lossy(output.as_slice()).into_string()
+}
+
+#[cfg(test)]
+mod test {
+    use libc;
+    use std::io::{File, TempDir};
+    use std::task;
+    use testing;
+    use super::{capture_fd, capture_stdout_in_child};
+
+    fn write(fd: libc::c_int, s: &str) {
+        unsafe {
+            libc::write(fd, s.as_ptr() as *const libc::c_void, s.len() as libc::size_t);
+        }
+    }
+
+    #[test]
+    fn restores_fd() {
+        let dir = TempDir::new("boehm-rs-test").unwrap();
+        let path = dir.path().join("target");
+        let fd = path.with_c_str(|p| unsafe {
+            libc::open(p, libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC,
+                       libc::S_IRUSR | libc::S_IWUSR)
+        });
+        assert!(fd >= 0);
+
+        assert_eq!(capture_fd(fd, || write(fd, "captured")).as_slice(), "captured");
+        write(fd, "a");
+
+        // the redirection is undone even if the captured code fails.
+        assert!(task::try(proc() {
+            capture_fd(fd, || fail!("oops"));
+        }).is_err());
+        write(fd, "b");
+
+        assert_eq!(capture_fd(fd, || write(fd, "again")).as_slice(), "again");
+        unsafe { libc::close(fd); }
+        assert_eq!(File::open(&path).read_to_string().unwrap().as_slice(), "ab");
+    }
+
+    #[test]
+    fn captures_child_stdout() {
+        testing::run(|| {
+            let out = unsafe {
+                capture_stdout_in_child(|| write(libc::STDOUT_FILENO, "from the child"))
+            };
+            assert_eq!(out.as_slice(), "from the child");
+
+            // a child that exits abnormally has its output discarded.
+            assert!(task::try(proc() unsafe {
+                capture_stdout_in_child(|| {
+                    write(libc::STDOUT_FILENO, "partial");
+                    libc::_exit(3);
+                });
+            }).is_err());
+        })
+    }
+}
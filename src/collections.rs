@@ -0,0 +1,575 @@
+//! Collections that hold GC objects weakly.
+
+use libc;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::{mem, ptr};
+use std::kinds::marker;
+use std::sync::atomics::{AtomicBool, AtomicUint, SeqCst};
+
+use ffi;
+use finalize;
+use weak;
+use {Gc, GcPinned, GcWeak, HiddenPtr};
+
+/// A map with GC objects as keys, compared by identity, that doesn't
+/// keep its keys alive: an entry disappears once its key has been
+/// collected.
+///
+/// Values are stored in uncollectable memory (see `GcPinned`), so any
+/// GC objects they reference stay alive. In particular, a value that
+/// references its own key keeps the entry alive forever; `Ephemeron`
+/// doesn't have that problem.
+///
+/// Entries for collected keys are purged lazily: when they're found
+/// while looking up a key, periodically on insertion, and by `purge`.
+pub struct WeakKeyMap<K, V> {
+    entries: HashMap<HiddenPtr<K>, (GcWeak<K>, GcPinned<V>)>,
+    inserts_since_purge: uint,
+}
+
+impl<K: 'static, V: 'static> WeakKeyMap<K, V> {
+    pub fn new() -> WeakKeyMap<K, V> {
+        WeakKeyMap { entries: HashMap::new(), inserts_since_purge: 0 }
+    }
+
+    /// The number of entries, including any whose keys have been
+    /// collected but that haven't been purged yet.
+    pub fn len(&self) -> uint {
+        self.entries.len()
+    }
+
+    /// Associate `value` with `key`, returning the previous value.
+    pub fn insert(&mut self, key: &Gc<K>, value: V) -> Option<V> {
+        let old = self.remove(key);
+
+        // purging all the entries once for every `len` insertions
+        // keeps the cost amortised O(1).
+        self.inserts_since_purge += 1;
+        if self.inserts_since_purge > self.entries.len() {
+            self.purge();
+        }
+
+        self.entries.insert(HiddenPtr::hide(key), (GcWeak::new(key), GcPinned::new(value)));
+        old
+    }
+
+    /// The value associated with `key`.
+    pub fn get<'a>(&'a mut self, key: &Gc<K>) -> Option<&'a V> {
+        self.get_mut(key).map(|v| &*v)
+    }
+
+    /// The value associated with `key`, mutably.
+    pub fn get_mut<'a>(&'a mut self, key: &Gc<K>) -> Option<&'a mut V> {
+        self.purge_stale(key);
+        self.entries.find_mut(&HiddenPtr::hide(key)).map(|entry| {
+            let (_, ref mut v) = *entry;
+            v.borrow_mut()
+        })
+    }
+
+    pub fn contains_key(&mut self, key: &Gc<K>) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Remove the entry for `key`, returning its value.
+    pub fn remove(&mut self, key: &Gc<K>) -> Option<V> {
+        self.purge_stale(key);
+        self.entries.pop(&HiddenPtr::hide(key)).map(|(_, v)| v.into_inner())
+    }
+
+    /// Remove all the entries whose keys have been collected,
+    /// returning how many there were.
+    pub fn purge(&mut self) -> uint {
+        self.inserts_since_purge = 0;
+        let dead: Vec<HiddenPtr<K>> = self.entries.iter()
+            .filter(|&(_, &(ref k, _))| !k.is_alive())
+            .map(|(h, _)| h.clone())
+            .collect();
+        for h in dead.iter() {
+            self.entries.remove(h);
+        }
+        dead.len()
+    }
+
+    // if there's an entry at the address of `key` whose key is dead,
+    // `key` is a new object that has reused the address.
+    fn purge_stale(&mut self, key: &Gc<K>) {
+        let h = HiddenPtr::hide(key);
+        let stale = match self.entries.find(&h) {
+            Some(&(ref k, _)) => !k.is_alive(),
+            None => false
+        };
+        if stale {
+            self.entries.remove(&h);
+        }
+    }
+}
+
+/// A map whose values are GC objects that it doesn't keep alive: an
+/// entry disappears once its value has been collected, e.g. for
+/// memoisation caches.
+///
+/// Keys are stored in normal (non-GC) memory, so shouldn't contain GC
+/// pointers. Entries with collected values are purged lazily: when
+/// they are looked up, periodically on insertion, and by `purge`.
+pub struct WeakValueMap<K, V> {
+    entries: HashMap<K, GcWeak<V>>,
+    inserts_since_purge: uint,
+}
+
+impl<K: Hash + Eq, V: 'static> WeakValueMap<K, V> {
+    pub fn new() -> WeakValueMap<K, V> {
+        WeakValueMap { entries: HashMap::new(), inserts_since_purge: 0 }
+    }
+
+    /// The number of entries, including any whose values have been
+    /// collected but that haven't been purged yet.
+    pub fn len(&self) -> uint {
+        self.entries.len()
+    }
+
+    /// Associate `value` with `key`, returning the previous value (if
+    /// it's still alive).
+    pub fn insert(&mut self, key: K, value: &Gc<V>) -> Option<Gc<V>> {
+        self.inserts_since_purge += 1;
+        if self.inserts_since_purge > self.entries.len() {
+            self.purge();
+        }
+
+        self.entries.swap(key, GcWeak::new(value)).and_then(|old| old.upgrade())
+    }
+
+    /// The value associated with `key`, if it's still alive.
+    pub fn get(&mut self, key: &K) -> Option<Gc<V>> {
+        let value = match self.entries.find(key) {
+            Some(weak) => weak.upgrade(),
+            None => return None
+        };
+        if value.is_none() {
+            self.entries.remove(key);
+        }
+        value
+    }
+
+    pub fn contains_key(&mut self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Remove the entry for `key`, returning its value (if it's still
+    /// alive).
+    pub fn remove(&mut self, key: &K) -> Option<Gc<V>> {
+        self.entries.pop(key).and_then(|weak| weak.upgrade())
+    }
+
+    /// Remove all the entries whose values have been collected,
+    /// returning how many there were.
+    pub fn purge(&mut self) -> uint {
+        self.inserts_since_purge = 0;
+        let before = self.entries.len();
+        let entries = mem::replace(&mut self.entries, HashMap::new());
+        self.entries = entries.move_iter().filter(|&(_, ref v)| v.is_alive()).collect();
+        before - self.entries.len()
+    }
+}
+
+/// A table associating values with GC objects (keys), in which the
+/// values don't keep their keys alive, even if they reference them.
+///
+/// That is, an entry is kept alive exactly as long as its key is
+/// reachable from outside the table: a value that points back to its
+/// key (directly or indirectly) doesn't stop both being collected,
+/// unlike with `WeakKeyMap`. This is the usual semantics for side
+/// tables of properties attached to objects.
+///
+/// Each value is allocated in its own GC object, whose address is
+/// hidden from the collector. The value is kept alive by a `NoOrder`
+/// finalizer that resurrects it for as long as its key is alive; once
+/// the key has been collected (which clears a disappearing link to
+/// it), the finalizer lets the value go too. So a value survives for
+/// up to two collections after its key dies, and, as for `Gc`, its
+/// destructor is not run. For the same reason, values can't be given
+/// finalizers of their own (`finalize::register` fails) while they
+/// are in the table.
+///
+/// Entries for collected keys are purged lazily, as for
+/// `WeakKeyMap`.
+pub struct Ephemeron<K, V> {
+    entries: HashMap<HiddenPtr<K>, *mut EphemeronCell>,
+    inserts_since_purge: uint,
+    mark: marker::NoSend,
+}
+
+// the state shared between a table entry and the value's finalizer,
+// in uncollectable, unscanned memory.
+struct EphemeronCell {
+    // a disappearing link to the key, holding its hidden address.
+    key: ffi::GC_word,
+    // the hidden address of the value.
+    value: ffi::GC_word,
+    // set when the entry is removed from the table, so the finalizer
+    // should stop resurrecting the value.
+    removed: AtomicBool,
+    // the table and the finalizer each hold a reference; the last one
+    // to let go frees the cell.
+    refs: AtomicUint,
+}
+
+impl<K: 'static, V: 'static> Ephemeron<K, V> {
+    pub fn new() -> Ephemeron<K, V> {
+        Ephemeron { entries: HashMap::new(), inserts_since_purge: 0, mark: marker::NoSend }
+    }
+
+    /// The number of entries, including any whose keys have been
+    /// collected but that haven't been purged yet.
+    pub fn len(&self) -> uint {
+        self.entries.len()
+    }
+
+    /// Associate `value` with `key`, replacing any previous value.
+    ///
+    /// Fails for values that need more than the default alignment
+    /// (which can't have finalizers), and if Java-style finalization
+    /// has been turned off (see `GcConfig::java_finalization`), since
+    /// resurrecting a value then wouldn't keep what it references
+    /// alive.
+    pub fn insert(&mut self, key: &Gc<K>, value: V) {
+        if ::is_overaligned::<V>() {
+            fail!("Ephemeron: values cannot be over-aligned")
+        }
+        if unsafe { ffi::GC_get_java_finalization() } == 0 {
+            fail!("Ephemeron: Java-style finalization must be enabled to keep values alive")
+        }
+        self.remove(key);
+
+        self.inserts_since_purge += 1;
+        if self.inserts_since_purge > self.entries.len() {
+            self.purge();
+        }
+
+        let value = Gc::new(value);
+        let has_finalizer = mem::size_of::<V>() != 0;
+        unsafe {
+            let cell = ffi::GC_malloc_atomic_uncollectable(
+                mem::size_of::<EphemeronCell>() as libc::size_t) as *mut EphemeronCell;
+            if cell.is_null() {
+                fail!("Could not allocate")
+            }
+            ptr::write(cell, EphemeronCell {
+                key: !(key.ptr as ffi::GC_word),
+                value: !(value.ptr as ffi::GC_word),
+                removed: AtomicBool::new(false),
+                // zero-sized values are never collected, so there's
+                // no finalizer to hold a reference.
+                refs: AtomicUint::new(if has_finalizer { 2 } else { 1 }),
+            });
+            // as with `GcWeak`, zero-sized keys are never collected, so
+            // don't need the link (and aren't in the heap to have one).
+            if mem::size_of::<K>() != 0 {
+                ffi::GC_general_register_disappearing_link(
+                    &mut (*cell).key as *mut ffi::GC_word as *mut *mut libc::c_void,
+                    ffi::GC_base(key.ptr as *mut libc::c_void));
+            }
+            if has_finalizer {
+                finalize::register_raw(value.ptr as *mut libc::c_void, finalize::NoOrder,
+                                       Some(keep_value_alive), cell as *mut libc::c_void);
+            }
+            self.entries.insert(HiddenPtr::hide(key), cell);
+        }
+    }
+
+    /// The value associated with `key`.
+    pub fn get(&mut self, key: &Gc<K>) -> Option<Gc<V>> {
+        self.purge_stale(key);
+        match self.entries.find(&HiddenPtr::hide(key)) {
+            Some(&cell) => unsafe {
+                Some(Gc { ptr: (!(*cell).value) as *mut V, mark: marker::NoSend })
+            },
+            None => None
+        }
+    }
+
+    pub fn contains_key(&mut self, key: &Gc<K>) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Remove the entry for `key`, returning its value.
+    pub fn remove(&mut self, key: &Gc<K>) -> Option<Gc<V>> {
+        let value = self.get(key);
+        match self.entries.pop(&HiddenPtr::hide(key)) {
+            Some(cell) => unsafe { remove_cell(cell) },
+            None => {}
+        }
+        value
+    }
+
+    /// Remove all the entries whose keys have been collected,
+    /// returning how many there were.
+    pub fn purge(&mut self) -> uint {
+        self.inserts_since_purge = 0;
+        let dead: Vec<HiddenPtr<K>> = self.entries.iter()
+            .filter(|&(_, &cell)| unsafe { key_is_dead(cell) })
+            .map(|(h, _)| h.clone())
+            .collect();
+        for h in dead.iter() {
+            match self.entries.pop(h) {
+                Some(cell) => unsafe { remove_cell(cell) },
+                None => {}
+            }
+        }
+        dead.len()
+    }
+
+    fn purge_stale(&mut self, key: &Gc<K>) {
+        let h = HiddenPtr::hide(key);
+        let stale = match self.entries.find(&h) {
+            Some(&cell) => unsafe { key_is_dead(cell) },
+            None => false
+        };
+        if stale {
+            match self.entries.pop(&h) {
+                Some(cell) => unsafe { remove_cell(cell) },
+                None => {}
+            }
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<K, V> Drop for Ephemeron<K, V> {
+    fn drop(&mut self) {
+        for (_, &cell) in self.entries.iter() {
+            unsafe { remove_cell(cell) }
+        }
+    }
+}
+
+unsafe fn key_is_dead(cell: *mut EphemeronCell) -> bool {
+    weak::read_link(&mut (*cell).key).is_null()
+}
+
+// the table is letting go of `cell`.
+unsafe fn remove_cell(cell: *mut EphemeronCell) {
+    (*cell).removed.store(true, SeqCst);
+    release_cell(cell)
+}
+
+unsafe fn release_cell(cell: *mut EphemeronCell) {
+    if (*cell).refs.fetch_sub(1, SeqCst) == 1 {
+        ffi::GC_unregister_disappearing_link(
+            &mut (*cell).key as *mut ffi::GC_word as *mut *mut libc::c_void);
+        ffi::GC_free(cell as *mut libc::c_void);
+    }
+}
+
+/// Whether `f` is the finalizer that `Ephemeron` uses to keep its
+/// values alive (for `finalize`, which mustn't replace it).
+#[doc(hidden)]
+pub fn is_value_finalizer(f: ffi::GC_finalization_proc) -> bool {
+    f as uint == keep_value_alive as uint
+}
+
+/// Give up the reference to `cell` (the client data of a value's
+/// finalizer, which has just been unregistered) if its entry has
+/// already been removed from the table, returning whether it was.
+#[doc(hidden)]
+pub unsafe fn release_value_finalizer(cell: *mut libc::c_void) -> bool {
+    let cell = cell as *mut EphemeronCell;
+    if (*cell).removed.load(SeqCst) {
+        release_cell(cell);
+        true
+    } else {
+        false
+    }
+}
+
+// the finalizer on each value, which runs whenever the value is only
+// reachable through the table.
+extern "C" fn keep_value_alive(value: *mut libc::c_void, cell: *mut libc::c_void) {
+    unsafe {
+        let cell = cell as *mut EphemeronCell;
+        if !(*cell).removed.load(SeqCst) && !key_is_dead(cell) {
+            // the key is still alive, so resurrect the value, to be
+            // checked again after the next collection.
+            finalize::register_raw(value, finalize::NoOrder, Some(keep_value_alive),
+                                   cell as *mut libc::c_void);
+        } else {
+            release_cell(cell)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use finalize;
+    use testing;
+    use {Gc, GcWeak, HiddenPtr};
+    use super::{WeakKeyMap, WeakValueMap, Ephemeron};
+
+    #[deriving(Clone)]
+    struct Node {
+        id: uint,
+    }
+
+    #[inline(never)]
+    fn insert_dead_keys(map: &mut WeakKeyMap<Node, uint>, n: uint) {
+        for i in range(0, n) {
+            map.insert(&Gc::new(Node { id: i }), i);
+        }
+    }
+
+    #[test]
+    fn weak_key_entries_disappear() {
+        testing::run(|| {
+            let mut map = WeakKeyMap::new();
+            let live = Gc::new(Node { id: 10 });
+            map.insert(&live, 10u);
+            insert_dead_keys(&mut map, 3);
+            testing::collect_fully();
+            assert_eq!(map.len(), 4);
+            assert_eq!(map.purge(), 3);
+            assert_eq!(map.len(), 1);
+            assert_eq!(map.get(&live), Some(&10));
+        })
+    }
+
+    #[inline(never)]
+    fn insert_dead_key(map: &mut WeakKeyMap<Node, uint>) -> HiddenPtr<Node> {
+        let key = Gc::new(Node { id: 0 });
+        map.insert(&key, 1);
+        HiddenPtr::hide(&key)
+    }
+
+    #[test]
+    fn weak_key_reused_address() {
+        testing::run(|| {
+            let mut map = WeakKeyMap::new();
+            let dead = insert_dead_key(&mut map);
+            testing::collect_fully();
+
+            // the collector hands out the dead key's memory again
+            // soon enough.
+            let mut reused = None;
+            for i in range(0u, 100000) {
+                let key = Gc::new(Node { id: i });
+                if HiddenPtr::hide(&key) == dead {
+                    reused = Some(key);
+                    break
+                }
+            }
+            let key = reused.expect("the dead key's address was never reused");
+
+            // the lookup notices that the entry belongs to the dead
+            // key, and removes it.
+            assert_eq!(map.get(&key), None);
+            assert_eq!(map.len(), 0);
+            map.insert(&key, 2);
+            assert_eq!(map.get(&key), Some(&2));
+        })
+    }
+
+    #[inline(never)]
+    fn insert_dead_value(map: &mut WeakValueMap<uint, Node>, key: uint) {
+        map.insert(key, &Gc::new(Node { id: key }));
+    }
+
+    #[test]
+    fn weak_value_entries_disappear() {
+        testing::run(|| {
+            let mut map = WeakValueMap::new();
+            let live = Gc::new(Node { id: 0 });
+            map.insert(0u, &live);
+            for i in range(1u, 4) {
+                insert_dead_value(&mut map, i);
+            }
+            testing::collect_fully();
+            assert_eq!(map.len(), 4);
+
+            // looking up a dead entry removes it.
+            assert!(map.get(&1).is_none());
+            assert_eq!(map.len(), 3);
+            assert_eq!(map.purge(), 2);
+            assert_eq!(map.len(), 1);
+            assert_eq!(map.get(&0).unwrap().borrow().id, 0);
+        })
+    }
+
+    // a value that points back to its key.
+    struct Prop {
+        key: Gc<Node>,
+    }
+
+    #[inline(never)]
+    fn make_cycle(eph: &mut Ephemeron<Node, Prop>) -> (GcWeak<Node>, GcWeak<Prop>) {
+        let key = Gc::new(Node { id: 1 });
+        eph.insert(&key, Prop { key: key.clone() });
+        let value = eph.get(&key).unwrap();
+        assert_eq!(value.borrow().key.borrow().id, 1);
+        (key.downgrade(), value.downgrade())
+    }
+
+    #[test]
+    fn value_pointing_to_key_is_collected() {
+        testing::run(|| {
+            let mut eph = Ephemeron::new();
+            let (key, value) = make_cycle(&mut eph);
+            testing::collect_fully();
+            assert!(!key.is_alive());
+            assert!(!value.is_alive());
+            eph.purge();
+            assert_eq!(eph.len(), 0);
+        })
+    }
+
+    #[test]
+    fn value_lives_with_key() {
+        testing::run(|| {
+            let mut eph = Ephemeron::new();
+            let key = Gc::new(Node { id: 2 });
+            eph.insert(&key, 3u);
+            testing::collect_fully();
+            assert_eq!(eph.purge(), 0);
+            assert_eq!(*eph.get(&key).unwrap().borrow(), 3);
+        })
+    }
+
+    struct Unit;
+
+    #[test]
+    fn zero_sized_key() {
+        testing::run(|| {
+            let mut eph = Ephemeron::new();
+            eph.insert(&Gc::new(Unit), 8u);
+            testing::collect_fully();
+            // every `Gc<Unit>` is the same, and is never collected.
+            assert_eq!(eph.purge(), 0);
+            assert_eq!(*eph.get(&Gc::new(Unit)).unwrap().borrow(), 8);
+            assert_eq!(*eph.remove(&Gc::new(Unit)).unwrap().borrow(), 8);
+            assert_eq!(eph.len(), 0);
+        })
+    }
+
+    #[test]
+    #[should_fail]
+    fn value_finalizer_is_private() {
+        testing::run(|| {
+            let mut eph = Ephemeron::new();
+            let key = Gc::new(Node { id: 4 });
+            eph.insert(&key, 5u);
+            finalize::register(&eph.get(&key).unwrap(), finalize::NoOrder, proc(_) {});
+        })
+    }
+
+    #[test]
+    fn removed_value_can_be_finalized() {
+        testing::run(|| {
+            let mut eph = Ephemeron::new();
+            let key = Gc::new(Node { id: 6 });
+            eph.insert(&key, 7u);
+            let value = eph.remove(&key).unwrap();
+            let (handle, previous) = finalize::register(&value, finalize::NoOrder, proc(_) {});
+            assert!(previous.is_none());
+            handle.cancel(&value);
+        })
+    }
+}
@@ -0,0 +1,167 @@
+//! Runtime configuration of the collector.
+
+use libc;
+use ffi;
+
+/// The default value of `large_object_threshold`.
+pub static DEFAULT_LARGE_OBJECT_THRESHOLD: uint = 100 * 1024;
+
+static mut LARGE_OBJECT_THRESHOLD: uint = DEFAULT_LARGE_OBJECT_THRESHOLD;
+
+/// The size (in bytes) at and above which `Gc::new` and
+/// `GcTracing::new` automatically switch to allocating as with their
+/// `new_large` counterparts.
+pub fn large_object_threshold() -> uint {
+    unsafe { LARGE_OBJECT_THRESHOLD }
+}
+
+/// A set of collector settings, built up with the methods below and
+/// then installed with `apply`. Settings that are left untouched keep
+/// whatever value the collector currently has.
+///
+/// ```rust,ignore
+/// boehm::init();
+/// boehm::GcConfig::new()
+///     .force_unmap_on_collect(true)
+///     .apply();
+/// ```
+///
+/// Some settings (marked below) can only be changed before the
+/// collector is initialised: use `init` rather than `boehm::init`
+/// followed by `apply` for those.
+#[deriving(Clone)]
+pub struct GcConfig {
+    force_unmap_on_collect: Option<bool>,
+    large_object_threshold: Option<uint>,
+    interior_pointers: Option<bool>,
+    finalize_on_demand: Option<bool>,
+    java_finalization: Option<bool>,
+}
+
+impl GcConfig {
+    /// A configuration that changes nothing.
+    pub fn new() -> GcConfig {
+        GcConfig {
+            force_unmap_on_collect: None,
+            large_object_threshold: None,
+            interior_pointers: None,
+            finalize_on_demand: None,
+            java_finalization: None,
+        }
+    }
+
+    /// Return unused heap pages to the operating system on every
+    /// explicit `collect`, not just on `collect_and_unmap`.
+    pub fn force_unmap_on_collect(mut self, yes: bool) -> GcConfig {
+        self.force_unmap_on_collect = Some(yes);
+        self
+    }
+
+    /// Allocate values of at least `bytes` bytes as large objects
+    /// (see `Gc::new_large`). Defaults to
+    /// `DEFAULT_LARGE_OBJECT_THRESHOLD`.
+    ///
+    /// This is not synchronised with allocations happening on other
+    /// threads, so should be set up before they start.
+    pub fn large_object_threshold(mut self, bytes: uint) -> GcConfig {
+        self.large_object_threshold = Some(bytes);
+        self
+    }
+
+    /// Whether pointers into the middle of an object keep it alive
+    /// (they do by default). Turning this off reduces the amount of
+    /// garbage retained by integers that happen to look like
+    /// pointers, especially in large heaps.
+    ///
+    /// All of this crate's pointer types still work, since they point
+    /// at the start of their object (a `GcRef` from `Gc::project`, and
+    /// a `GcCursor`, keep hold of such a pointer as well as the
+    /// interior one). Boehm itself registers the offsets of the debug
+    /// allocator's header and of `GC_memalign`'s result. See
+    /// `tests/interior_pointers.rs`.
+    ///
+    /// This can only be set before initialisation, with `init`.
+    pub fn interior_pointers(mut self, yes: bool) -> GcConfig {
+        self.interior_pointers = Some(yes);
+        self
+    }
+
+    /// Only run finalizers when explicitly asked to by
+    /// `finalize::run_finalizers`, rather than automatically during
+    /// allocation (see `finalize::pending_finalizers`).
+    pub fn finalize_on_demand(mut self, yes: bool) -> GcConfig {
+        self.finalize_on_demand = Some(yes);
+        self
+    }
+
+    /// Use Java-like finalization semantics (`GC_java_finalization`):
+    /// once the finalizable objects have been found, everything
+    /// reachable from them is marked in a separate pass, so unordered
+    /// finalizers never see objects that have already been reclaimed,
+    /// and a finalizer can safely resurrect what it references. This
+    /// is needed for `FinalizerOrder::Unreachable` to behave as
+    /// documented, for `NoOrder` finalizers to resurrect their object
+    /// (without it, only the object itself survives, not what it
+    /// points to), and so for `Ephemeron`, which fails if this is
+    /// off. It is usually on by default.
+    ///
+    /// With the `debug` feature, objects that are resurrected by their
+    /// own finalizers are reported (see
+    /// `finalize::check_resurrections`).
+    pub fn java_finalization(mut self, yes: bool) -> GcConfig {
+        self.java_finalization = Some(yes);
+        self
+    }
+
+    /// Initialise the collector (as with `boehm::init`) using these
+    /// settings, including those that must be set beforehand.
+    ///
+    /// Fails if one of the latter is used and the collector has
+    /// already been initialised (including implicitly, by an
+    /// allocation).
+    pub fn init(&self) {
+        unsafe {
+            match self.interior_pointers {
+                Some(_) if ffi::GC_is_init_called() != 0 => {
+                    fail!("GcConfig::init: interior_pointers must be set before the collector \
+                           is initialised")
+                }
+                Some(b) => ffi::GC_set_all_interior_pointers(b as libc::c_int),
+                None => {}
+            }
+        }
+        ::init();
+        self.apply();
+    }
+
+    /// Install these settings in the collector.
+    ///
+    /// Fails if a setting that must be set before initialisation is
+    /// used after it.
+    pub fn apply(&self) {
+        unsafe {
+            match self.interior_pointers {
+                Some(b) if (ffi::GC_get_all_interior_pointers() != 0) != b => {
+                    fail!("GcConfig: interior_pointers must be set by GcConfig::init")
+                }
+                _ => {}
+            }
+            match self.force_unmap_on_collect {
+                Some(b) => ffi::GC_set_force_unmap_on_gcollect(b as libc::c_int),
+                None => {}
+            }
+            match self.finalize_on_demand {
+                Some(b) => ffi::GC_set_finalize_on_demand(b as libc::c_int),
+                None => {}
+            }
+            match self.java_finalization {
+                Some(b) => ffi::GC_set_java_finalization(b as libc::c_int),
+                None => {}
+            }
+            match self.large_object_threshold {
+                Some(n) => LARGE_OBJECT_THRESHOLD = n,
+                None => {}
+            }
+        }
+    }
+}
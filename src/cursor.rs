@@ -0,0 +1,124 @@
+//! Checked pointer arithmetic within GC objects.
+
+use libc;
+use std::mem;
+use std::kinds::marker;
+
+use ffi;
+use ptr_check;
+use Gc;
+
+/// A pointer to a `T` inside a GC allocation that can be moved
+/// around with pointer arithmetic, e.g. for walking a buffer. It
+/// keeps the object alive, even with interior pointers disabled.
+///
+/// With the `debug` feature enabled, every move is checked (with
+/// Boehm's `GC_pre_incr`/`GC_post_incr`) to stay within the object
+/// that the cursor started in, failing if it doesn't. Otherwise, it is
+/// plain (unchecked) pointer arithmetic.
+///
+/// With interior pointers enabled (the default), the collector pads
+/// each object by a byte, so a pointer to one-past-the-end of it is
+/// still inside it and doesn't count as leaving it; with them disabled
+/// (see `GcConfig::interior_pointers`), it does.
+pub struct GcCursor<T> {
+    // a pointer to the start of the object, which keeps it alive
+    // wherever `ptr` is.
+    base: *const u8,
+    ptr: *mut T,
+    mark: marker::NoSend
+}
+
+impl<T> GcCursor<T> {
+    /// A cursor pointing at the start of `gc`, reading it as a
+    /// sequence of `T`s.
+    pub fn new<U: 'static>(gc: &Gc<U>) -> GcCursor<T> {
+        GcCursor { base: gc.ptr as *const u8, ptr: gc.ptr as *mut T, mark: marker::NoSend }
+    }
+
+    /// A cursor at `ptr`, which must point into an allocation in the
+    /// GC heap.
+    pub unsafe fn from_raw(ptr: *mut T) -> GcCursor<T> {
+        let base = ffi::GC_base(ptr as *mut libc::c_void) as *const u8;
+        GcCursor { base: base, ptr: ptr, mark: marker::NoSend }
+    }
+
+    /// The current position.
+    #[inline]
+    pub fn get(&self) -> *mut T {
+        self.ptr
+    }
+
+    /// Move `n` elements along, returning the new position (like
+    /// `p += n`).
+    pub fn pre_incr(&mut self, n: int) -> *mut T {
+        self.move_by(n, true)
+    }
+
+    /// Move `n` elements along, returning the old position (like
+    /// `p++`).
+    pub fn post_incr(&mut self, n: int) -> *mut T {
+        self.move_by(n, false)
+    }
+
+    fn move_by(&mut self, n: int, pre: bool) -> *mut T {
+        let old = self.ptr;
+        let bytes = n * mem::size_of::<T>() as int;
+
+        if cfg!(feature = "debug") {
+            let mut p = self.ptr as *mut libc::c_void;
+            let failed = ptr_check::checking(|| unsafe {
+                if pre {
+                    ffi::GC_pre_incr(&mut p, bytes as libc::ptrdiff_t);
+                } else {
+                    ffi::GC_post_incr(&mut p, bytes as libc::ptrdiff_t);
+                }
+            });
+            if failed {
+                fail!("GcCursor: moving {} bytes from {:p} leaves the GC object", bytes, old)
+            }
+            self.ptr = p as *mut T;
+        } else {
+            self.ptr = (self.ptr as int + bytes) as *mut T;
+        }
+
+        if pre { self.ptr } else { old }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use testing;
+    use Gc;
+    use super::GcCursor;
+
+    #[test]
+    fn walk_buffer() {
+        testing::run(|| {
+            let mut buf = [0u32, .. 16];
+            for (i, x) in buf.mut_iter().enumerate() {
+                *x = i as u32;
+            }
+            let gc = Gc::new(buf);
+            let mut cursor: GcCursor<u32> = GcCursor::new(&gc);
+            let mut sum = 0;
+            for _ in range(0u, 16) {
+                sum += unsafe { *cursor.post_incr(1) };
+            }
+            assert_eq!(sum, 120);
+            assert_eq!(unsafe { *cursor.pre_incr(-1) }, 15);
+            assert_eq!(cursor.get() as uint - gc.borrow() as *const [u32, .. 16] as uint, 60);
+        })
+    }
+
+    #[test]
+    #[should_fail]
+    #[cfg(feature = "debug")]
+    fn leaving_the_object() {
+        testing::run(|| {
+            let gc = Gc::new([0u32, .. 16]);
+            let mut cursor: GcCursor<u32> = GcCursor::new(&gc);
+            cursor.pre_incr(100);
+        })
+    }
+}
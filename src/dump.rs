@@ -0,0 +1,289 @@
+//! Structured versions of the collector's `GC_dump` output.
+
+use std::num;
+
+use ffi;
+use capture;
+
+/// Run `GC_dump` and return what it printed (rather than letting it
+/// go to stdout, as `debug_dump` does).
+///
+/// `GC_dump` can only write to the process's stdout, so rather than
+/// redirecting that (which would swallow everything that other
+/// threads print in the meantime), this forks, and has the child
+/// process (which has a copy of the heap) print the dump into a pipe.
+/// So this is only supported on Unix, and costs a `fork` of the
+/// whole process.
+pub fn dump_to_string() -> String {
+    unsafe { capture::capture_stdout_in_child(|| ffi::GC_dump()) }
+}
+
+/// Run `GC_dump` and parse its output. This forks, as
+/// `dump_to_string` does.
+pub fn heap_dump() -> HeapDump {
+    HeapDump::parse(dump_to_string().as_slice())
+}
+
+/// A region of memory that the collector scans for roots.
+#[deriving(Clone, PartialEq, Show)]
+pub struct RootSet {
+    pub start: uint,
+    pub end: uint,
+    /// Whether the region is only registered until the next
+    /// collection.
+    pub temporary: bool,
+}
+
+/// One of the collector's lists of free heap blocks.
+#[deriving(Clone, PartialEq, Show)]
+pub struct FreeList {
+    /// The index of the list (lists are grouped by block size).
+    pub index: uint,
+    /// The total number of bytes of the blocks on the list.
+    pub total_size: uint,
+    /// The number of blocks on the list.
+    pub blocks: uint,
+}
+
+/// The in-use blocks holding objects of a single kind and size.
+#[deriving(Clone, PartialEq, Show)]
+pub struct SizeClass {
+    /// The object kind: 0 for pointer-free objects, 1 for normal
+    /// ones, 2 for uncollectable ones, and others for kinds such as
+    /// typed objects.
+    pub kind: uint,
+    /// The size of each object, in bytes.
+    pub size: uint,
+    /// The number of blocks.
+    pub blocks: uint,
+    /// The number of marked objects across those blocks.
+    pub marks: uint,
+}
+
+/// The information printed by `GC_dump`, in structured form. Fields
+/// are `None` (or empty) when the corresponding line couldn't be
+/// found, since the exact format varies between Boehm versions.
+#[deriving(Clone, Show)]
+pub struct HeapDump {
+    pub static_roots: Vec<RootSet>,
+    /// The total size of the static roots, in bytes.
+    pub root_size: Option<uint>,
+    pub heap_size: Option<uint>,
+    pub free_lists: Vec<FreeList>,
+    /// The total size of all the free lists, in bytes.
+    pub free_bytes: Option<uint>,
+    pub size_classes: Vec<SizeClass>,
+    pub blocks_in_use: Option<uint>,
+    pub bytes_in_use: Option<uint>,
+    /// The number of objects with registered finalizers.
+    pub finalizable_objects: Option<uint>,
+    pub disappearing_links: Option<uint>,
+    /// The number of objects queued for finalization.
+    pub finalization_queue: Option<uint>,
+    pub links_cleared: Option<uint>,
+    /// The unparsed output.
+    pub raw: String,
+}
+
+#[deriving(PartialEq)]
+enum Section {
+    Preamble, StaticRoots, HeapSections, FreeBlocks, BlocksInUse, Finalization
+}
+
+impl HeapDump {
+    /// Parse the output of `GC_dump`.
+    pub fn parse(dump: &str) -> HeapDump {
+        let mut d = HeapDump {
+            static_roots: vec![],
+            root_size: None,
+            heap_size: None,
+            free_lists: vec![],
+            free_bytes: None,
+            size_classes: vec![],
+            blocks_in_use: None,
+            bytes_in_use: None,
+            finalizable_objects: None,
+            disappearing_links: None,
+            finalization_queue: None,
+            links_cleared: None,
+            raw: dump.to_string(),
+        };
+
+        let mut section = Preamble;
+        for line in dump.lines() {
+            let line = line.trim();
+            if line.starts_with("***") {
+                section = match line {
+                    "***Static roots:" => StaticRoots,
+                    "***Heap sections:" => HeapSections,
+                    "***Free blocks:" => FreeBlocks,
+                    "***Blocks in use:" => BlocksInUse,
+                    "***Finalization statistics:" => Finalization,
+                    _ => Preamble
+                };
+                continue
+            }
+
+            let n = numbers(line);
+            match section {
+                StaticRoots => {
+                    if line.starts_with("From ") && n.len() >= 2 {
+                        d.static_roots.push(RootSet {
+                            start: n[0],
+                            end: n[1],
+                            temporary: line.contains("temporary")
+                        })
+                    } else if line.starts_with("Total size") {
+                        d.root_size = n.get(0).map(|&x| x);
+                    }
+                }
+                HeapSections => {
+                    if line.starts_with("Total heap size") {
+                        d.heap_size = n.get(0).map(|&x| x);
+                    }
+                }
+                FreeBlocks => {
+                    if line.starts_with("Free list") && n.len() >= 2 {
+                        d.free_lists.push(FreeList { index: n[0], total_size: n[1], blocks: 0 })
+                    } else if line.starts_with("0x") {
+                        match d.free_lists.mut_last() {
+                            Some(l) => l.blocks += 1,
+                            None => {}
+                        }
+                    } else if line.starts_with("Total of") {
+                        d.free_bytes = n.get(0).map(|&x| x);
+                    }
+                }
+                BlocksInUse => {
+                    if line.starts_with("blocks =") && n.len() >= 2 {
+                        d.blocks_in_use = Some(n[0]);
+                        d.bytes_in_use = Some(n[1]);
+                    } else if !line.starts_with("(kind") {
+                        // a run of `(kind:size,marks)` entries, one per block.
+                        for entry in line.split('(').skip(1) {
+                            let e = numbers(entry);
+                            if e.len() < 3 { continue }
+                            match d.size_classes.mut_iter()
+                                   .find(|c| c.kind == e[0] && c.size == e[1]) {
+                                Some(c) => {
+                                    c.blocks += 1;
+                                    c.marks += e[2];
+                                    continue
+                                }
+                                None => {}
+                            }
+                            d.size_classes.push(SizeClass {
+                                kind: e[0], size: e[1], blocks: 1, marks: e[2]
+                            })
+                        }
+                    }
+                }
+                Finalization => {
+                    if line.contains("finalization table entries") && n.len() >= 2 {
+                        d.finalizable_objects = Some(n[0]);
+                        d.disappearing_links = Some(n[1]);
+                    } else if line.contains("eligible for immediate finalization") &&
+                              n.len() >= 2 {
+                        d.finalization_queue = Some(n[0]);
+                        d.links_cleared = Some(n[1]);
+                    }
+                }
+                Preamble => {}
+            }
+        }
+
+        d.size_classes.sort_by(|a, b| (a.kind, a.size).cmp(&(b.kind, b.size)));
+        d
+    }
+}
+
+/// All the (decimal or `0x`-prefixed hexadecimal) numbers in `line`,
+/// in order.
+fn numbers(line: &str) -> Vec<uint> {
+    line.split(|c: char| !c.is_alphanumeric())
+        .filter_map(|word| {
+            if word.starts_with("0x") {
+                num::from_str_radix::<uint>(word.slice_from(2), 16)
+            } else {
+                from_str::<uint>(word)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HeapDump, RootSet, FreeList, SizeClass, heap_dump};
+    use testing;
+
+    // trimmed from the output of `GC_dump` (Boehm 7.2).
+    static SAMPLE: &'static str = "\
+***Static roots:
+From 0x601000 to 0x602238 
+From 0x7f2b3c41e000 to 0x7f2b3c420000  (temporary)
+Total size: 12856
+
+***Heap sections:
+Total heap size: 262144
+Section 0 from 0x1d5b000 to 0x1d9b000 0/64 blacklisted
+
+***Free blocks:
+Free list 60 (total size 196608):
+\t0x1d6b000 size 196608 not black listed
+Total of 196608 bytes on free list
+
+***Blocks in use:
+(kind(0=ptrfree,1=normal,2=unc.):size_in_bytes, #_marks_set)
+(1:16,12)(1:32,3)(0:16,0)(1:16,7)
+blocks = 4, bytes = 16384
+
+***Finalization statistics:
+3 finalization table entries; 1 disappearing links alive
+0 objects are eligible for immediate finalization; 2 links cleared
+";
+
+    #[test]
+    fn parse_sample() {
+        let d = HeapDump::parse(SAMPLE);
+        assert_eq!(d.static_roots,
+                   vec![RootSet { start: 0x601000, end: 0x602238, temporary: false },
+                        RootSet { start: 0x7f2b3c41e000, end: 0x7f2b3c420000,
+                                  temporary: true }]);
+        assert_eq!(d.root_size, Some(12856));
+        assert_eq!(d.heap_size, Some(262144));
+        assert_eq!(d.free_lists,
+                   vec![FreeList { index: 60, total_size: 196608, blocks: 1 }]);
+        assert_eq!(d.free_bytes, Some(196608));
+        assert_eq!(d.size_classes,
+                   vec![SizeClass { kind: 0, size: 16, blocks: 1, marks: 0 },
+                        SizeClass { kind: 1, size: 16, blocks: 2, marks: 19 },
+                        SizeClass { kind: 1, size: 32, blocks: 1, marks: 3 }]);
+        assert_eq!(d.blocks_in_use, Some(4));
+        assert_eq!(d.bytes_in_use, Some(16384));
+        assert_eq!(d.finalizable_objects, Some(3));
+        assert_eq!(d.disappearing_links, Some(1));
+        assert_eq!(d.finalization_queue, Some(0));
+        assert_eq!(d.links_cleared, Some(2));
+        assert_eq!(d.raw.as_slice(), SAMPLE);
+    }
+
+    #[test]
+    fn parse_garbage() {
+        let d = HeapDump::parse("not a heap dump\n***Unknown section:\n1 2 3\n");
+        assert!(d.static_roots.is_empty());
+        assert_eq!(d.heap_size, None);
+        assert_eq!(d.blocks_in_use, None);
+    }
+
+    #[test]
+    fn dump_the_real_heap() {
+        testing::run(|| {
+            let _x = ::Gc::new(1u);
+            let d = heap_dump();
+            // the dump's total counts unmapped sections, `heap_size` doesn't.
+            assert_eq!(d.heap_size, Some(::heap_size() + ::unmapped_bytes()));
+            assert!(d.blocks_in_use.unwrap() > 0);
+            assert!(d.bytes_in_use.unwrap() <= d.heap_size.unwrap());
+        })
+    }
+}
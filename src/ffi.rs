@@ -13,7 +13,7 @@ pub type GC_warn_proc = extern "C" fn(arg1: *mut c_schar, arg2: GC_word);
 pub type GC_hidden_pointer = GC_word;
 pub type GC_fn_type = extern "C" fn(arg1: *mut c_void) -> *mut c_void;
 pub struct Struct_GC_stack_base {
-    mem_base: *mut c_void,
+    pub mem_base: *mut c_void,
 }
 pub type GC_stack_base_func =
     extern "C" fn(arg1: *mut Struct_GC_stack_base, arg2: *mut c_void)
@@ -80,7 +80,11 @@ extern "C" {
     pub fn GC_set_pages_executable(arg1: c_int);
     pub fn GC_get_pages_executable() -> c_int;
     pub fn GC_set_handle_fork(arg1: c_int);
+    pub fn GC_atfork_prepare();
+    pub fn GC_atfork_parent();
+    pub fn GC_atfork_child();
     pub fn GC_init();
+    pub fn GC_is_init_called() -> c_int;
     pub fn GC_malloc(arg1: size_t) -> *mut c_void;
     pub fn GC_malloc_atomic(arg1: size_t) -> *mut c_void;
     pub fn GC_strdup(arg1: *const c_schar) -> *mut c_schar;
@@ -153,47 +157,47 @@ extern "C" {
     pub fn GC_debug_realloc_replacement(arg1: *mut c_void, arg2: size_t) ->
      *mut c_void;
     pub fn GC_register_finalizer(arg1: *mut c_void,
-                                 arg2: GC_finalization_proc,
+                                 arg2: Option<GC_finalization_proc>,
                                  arg3: *mut c_void,
-                                 arg4: *mut GC_finalization_proc,
+                                 arg4: *mut Option<GC_finalization_proc>,
                                  arg5: *mut *mut c_void);
     pub fn GC_debug_register_finalizer(arg1: *mut c_void,
-                                       arg2: GC_finalization_proc,
+                                       arg2: Option<GC_finalization_proc>,
                                        arg3: *mut c_void,
-                                       arg4: *mut GC_finalization_proc,
+                                       arg4: *mut Option<GC_finalization_proc>,
                                        arg5: *mut *mut c_void);
     pub fn GC_register_finalizer_ignore_self(arg1: *mut c_void,
-                                             arg2: GC_finalization_proc,
+                                             arg2: Option<GC_finalization_proc>,
                                              arg3: *mut c_void,
-                                             arg4: *mut GC_finalization_proc,
+                                             arg4: *mut Option<GC_finalization_proc>,
                                              arg5: *mut *mut c_void);
     pub fn GC_debug_register_finalizer_ignore_self(arg1: *mut c_void,
-                                                   arg2: GC_finalization_proc,
+                                                   arg2: Option<GC_finalization_proc>,
                                                    arg3: *mut c_void,
                                                    arg4:
-                                                       *mut GC_finalization_proc,
+                                                       *mut Option<GC_finalization_proc>,
                                                    arg5: *mut *mut c_void);
     pub fn GC_register_finalizer_no_order(arg1: *mut c_void,
-                                          arg2: GC_finalization_proc,
+                                          arg2: Option<GC_finalization_proc>,
                                           arg3: *mut c_void,
-                                          arg4: *mut GC_finalization_proc,
+                                          arg4: *mut Option<GC_finalization_proc>,
                                           arg5: *mut *mut c_void);
     pub fn GC_debug_register_finalizer_no_order(arg1: *mut c_void,
-                                                arg2: GC_finalization_proc,
+                                                arg2: Option<GC_finalization_proc>,
                                                 arg3: *mut c_void,
                                                 arg4:
-                                                    *mut GC_finalization_proc,
+                                                    *mut Option<GC_finalization_proc>,
                                                 arg5: *mut *mut c_void);
     pub fn GC_register_finalizer_unreachable(arg1: *mut c_void,
-                                             arg2: GC_finalization_proc,
+                                             arg2: Option<GC_finalization_proc>,
                                              arg3: *mut c_void,
-                                             arg4: *mut GC_finalization_proc,
+                                             arg4: *mut Option<GC_finalization_proc>,
                                              arg5: *mut *mut c_void);
     pub fn GC_debug_register_finalizer_unreachable(arg1: *mut c_void,
-                                                   arg2: GC_finalization_proc,
+                                                   arg2: Option<GC_finalization_proc>,
                                                    arg3: *mut c_void,
                                                    arg4:
-                                                       *mut GC_finalization_proc,
+                                                       *mut Option<GC_finalization_proc>,
                                                    arg5: *mut *mut c_void);
     pub fn GC_register_disappearing_link(arg1: *mut *mut c_void) -> c_int;
     pub fn GC_general_register_disappearing_link(arg1: *mut *mut c_void,
@@ -212,6 +216,9 @@ extern "C" {
     pub fn GC_call_with_gc_active(arg1: GC_fn_type, arg2: *mut c_void) ->
      *mut c_void;
     pub fn GC_get_stack_base(arg1: *mut Struct_GC_stack_base) -> c_int;
+    pub fn GC_allow_register_threads();
+    pub fn GC_register_my_thread(arg1: *const Struct_GC_stack_base) -> c_int;
+    pub fn GC_unregister_my_thread() -> c_int;
     pub fn GC_same_obj(arg1: *mut c_void, arg2: *mut c_void) -> *mut c_void;
     pub fn GC_pre_incr(arg1: *mut *mut c_void, arg2: ptrdiff_t) ->
      *mut c_void;
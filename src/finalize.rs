@@ -0,0 +1,916 @@
+//! Running code when objects are collected.
+//!
+//! A finalizer is a closure that is run (at most once) with an object
+//! after the collector has found it to be unreachable. The object's
+//! memory is only reclaimed at a later collection, after the
+//! finalizer has run (and only if it is still unreachable then).
+//!
+//! Finalizers are run by whichever thread happens to trigger them
+//! (e.g. by allocating), or by a dedicated thread (see
+//! `spawn_finalizer_thread`), so they need to be `Send`. A finalizer
+//! that fails doesn't stop others from running.
+
+use libc;
+use std::{mem, ptr};
+use std::intrinsics::TypeId;
+use std::rt::mutex::{StaticNativeMutex, NATIVE_MUTEX_INIT};
+use std::rt::local::Local;
+use std::rt::task::Task;
+use std::rt::unwind;
+use std::sync::atomics::{AtomicBool, AtomicUint, INIT_ATOMIC_BOOL, INIT_ATOMIC_UINT, SeqCst};
+use std::task::TaskBuilder;
+use time;
+
+use collections;
+use ffi;
+use ptr_check;
+use warn;
+use {Gc, HiddenPtr};
+
+static mut FINALIZERS_RUN: AtomicUint = INIT_ATOMIC_UINT;
+static mut FINALIZER_PANICS: AtomicUint = INIT_ATOMIC_UINT;
+static mut BATCHES: AtomicUint = INIT_ATOMIC_UINT;
+static mut LAST_BATCH: AtomicUint = INIT_ATOMIC_UINT;
+static mut TOTAL_NS: AtomicUint = INIT_ATOMIC_UINT;
+static mut MAX_BATCH_NS: AtomicUint = INIT_ATOMIC_UINT;
+
+/// Counters describing the finalizers that have been run.
+#[deriving(Clone, Show)]
+pub struct FinalizerStats {
+    /// The number of finalizers registered with `register` that have
+    /// been run.
+    pub runs: uint,
+    /// How many of those failed.
+    pub panics: uint,
+    /// The number of times the finalizer thread has drained the
+    /// queue.
+    pub batches: uint,
+    /// The number of finalizers run in the last batch, i.e. the
+    /// length of the queue when it was last drained.
+    pub last_batch: uint,
+    /// The total time the finalizer thread has spent running
+    /// finalizers, in nanoseconds.
+    pub total_ns: u64,
+    /// The longest time taken by a single batch, in nanoseconds.
+    pub max_batch_ns: u64,
+    /// Whether there are finalizers waiting to be run right now.
+    pub pending: bool,
+}
+
+/// The current finalization statistics.
+pub fn stats() -> FinalizerStats {
+    unsafe {
+        FinalizerStats {
+            runs: FINALIZERS_RUN.load(SeqCst),
+            panics: FINALIZER_PANICS.load(SeqCst),
+            batches: BATCHES.load(SeqCst),
+            last_batch: LAST_BATCH.load(SeqCst),
+            total_ns: TOTAL_NS.load(SeqCst) as u64,
+            max_batch_ns: MAX_BATCH_NS.load(SeqCst) as u64,
+            pending: pending_finalizers(),
+        }
+    }
+}
+
+/// Whether there are finalizers waiting to be run.
+///
+/// Finalizers are normally run automatically during allocation; with
+/// `GcConfig::finalize_on_demand(true)` they are instead queued until
+/// `run_finalizers` is called, e.g. from an event loop at a point
+/// where it is safe for them to run.
+pub fn pending_finalizers() -> bool {
+    unsafe { ffi::GC_should_invoke_finalizers() != 0 }
+}
+
+/// Run all queued finalizers, returning how many were run.
+pub fn run_finalizers() -> uint {
+    unsafe { ffi::GC_invoke_finalizers() as uint }
+}
+
+/// A closure that is called when finalizers are queued (see
+/// `set_finalizer_notifier`).
+pub type FinalizerNotifier = Box<Fn() + Send>;
+
+// the current notifier (or null), protected by `NOTIFIER_LOCK`, which
+// also serialises calls to it.
+static mut NOTIFIER_LOCK: StaticNativeMutex = NATIVE_MUTEX_INIT;
+static mut NOTIFIER: *mut FinalizerNotifier = 0 as *mut FinalizerNotifier;
+
+/// Call `f` whenever finalizers are queued (only with
+/// `GcConfig::finalize_on_demand(true)`). This is called from within
+/// the collector, and so shouldn't allocate GC memory or run the
+/// finalizers itself: it should just arrange for `run_finalizers` to
+/// be called soon, e.g. by waking up an event loop:
+///
+/// ```rust,ignore
+/// let (tx, rx) = channel();
+/// boehm::set_finalizer_notifier(box move |&:| tx.send(()));
+/// ```
+///
+/// Returns the previous notifier, if any.
+pub fn set_finalizer_notifier(f: FinalizerNotifier) -> Option<FinalizerNotifier> {
+    unsafe {
+        let old = replace_notifier(mem::transmute(box f));
+        ffi::GC_set_finalizer_notifier(notify);
+        old
+    }
+}
+
+/// Remove the notifier installed by `set_finalizer_notifier`,
+/// returning it.
+pub fn clear_finalizer_notifier() -> Option<FinalizerNotifier> {
+    unsafe { replace_notifier(0 as *mut FinalizerNotifier) }
+}
+
+unsafe fn replace_notifier(f: *mut FinalizerNotifier) -> Option<FinalizerNotifier> {
+    let _guard = NOTIFIER_LOCK.lock();
+    let old = NOTIFIER;
+    NOTIFIER = f;
+    if old.is_null() {
+        None
+    } else {
+        let old: Box<FinalizerNotifier> = mem::transmute(old);
+        Some(*old)
+    }
+}
+
+extern "C" fn notify() {
+    unsafe {
+        let _guard = NOTIFIER_LOCK.lock();
+        if NOTIFIER.is_null() { return }
+        // failure can't unwind through the collector.
+        let f = NOTIFIER;
+        if unwind::try(|| (*f).call(())).is_err() {
+            reset_unwinding();
+        }
+    }
+}
+
+// the state shared between the notifier and the finalizer thread,
+// protected by (and signalled with) `THREAD_LOCK`.
+static mut THREAD_LOCK: StaticNativeMutex = NATIVE_MUTEX_INIT;
+static mut WAKEUPS: uint = 0;
+static mut SHUTDOWN: bool = false;
+static mut THREAD_RUNNING: AtomicBool = INIT_ATOMIC_BOOL;
+
+/// A background thread that runs finalizers as they are queued.
+/// Created by `spawn_finalizer_thread`.
+///
+/// Dropping this shuts the thread down (as `shutdown` does).
+pub struct FinalizerThread {
+    done: Receiver<()>,
+    previous_notifier: Option<FinalizerNotifier>,
+    previous_on_demand: bool,
+    finished: bool,
+}
+
+/// Start a thread that runs finalizers whenever they are queued,
+/// rather than having them run (at unpredictable times) by whichever
+/// thread is allocating.
+///
+/// This switches the collector to finalize on demand (see
+/// `GcConfig::finalize_on_demand`) and installs a finalizer notifier;
+/// the previous setting and notifier are restored on shutdown. Only
+/// one finalizer thread can exist at a time.
+///
+/// This is unsafe because every finalizer then runs on this thread,
+/// and so touches its object from a thread other than the one that
+/// allocated it, even if the object isn't `Send` (e.g. it contains an
+/// `Rc`, or `Gc` pointers into a graph that the owning thread is still
+/// using). The caller must ensure that all of the finalizers that are
+/// registered (see `register`) are safe to run concurrently with the
+/// rest of the program.
+pub unsafe fn spawn_finalizer_thread() -> FinalizerThread {
+    if THREAD_RUNNING.swap(true, SeqCst) {
+        fail!("spawn_finalizer_thread: a finalizer thread is already running")
+    }
+    let previous_on_demand = ffi::GC_get_finalize_on_demand() != 0;
+    ffi::GC_set_finalize_on_demand(1);
+    ffi::GC_allow_register_threads();
+
+    let previous_notifier = set_finalizer_notifier(box |&:| wake_finalizer_thread());
+    let (tx, rx) = channel();
+    TaskBuilder::new().named("boehm-finalizer").spawn(proc() {
+        unsafe {
+            let mut sb = ffi::Struct_GC_stack_base { mem_base: ptr::null_mut() };
+            ffi::GC_get_stack_base(&mut sb);
+            ffi::GC_register_my_thread(&sb as *const ffi::Struct_GC_stack_base);
+        }
+
+        // finalizers may have been queued before the notifier was
+        // installed, without a wakeup.
+        run_batch();
+
+        loop {
+            let shutdown = unsafe {
+                let guard = THREAD_LOCK.lock();
+                while WAKEUPS == 0 && !SHUTDOWN {
+                    guard.wait();
+                }
+                WAKEUPS = 0;
+                SHUTDOWN
+            };
+            // this runs everything that's queued, including anything
+            // queued before shutdown was requested.
+            run_batch();
+            if shutdown { break }
+        }
+
+        unsafe { ffi::GC_unregister_my_thread(); }
+        tx.send(());
+    });
+
+    FinalizerThread {
+        done: rx,
+        previous_notifier: previous_notifier,
+        previous_on_demand: previous_on_demand,
+        finished: false,
+    }
+}
+
+impl FinalizerThread {
+    /// Stop the thread, after it has run any finalizers that are
+    /// currently queued. Ones queued after that are run straight
+    /// away, unless finalizers were previously being run on demand.
+    pub fn shutdown(mut self) {
+        self.stop()
+    }
+
+    fn stop(&mut self) {
+        if self.finished { return }
+        self.finished = true;
+
+        unsafe {
+            let guard = THREAD_LOCK.lock();
+            SHUTDOWN = true;
+            guard.signal();
+        }
+        self.done.recv();
+
+        match self.previous_notifier.take() {
+            Some(f) => { set_finalizer_notifier(f); }
+            None => { clear_finalizer_notifier(); }
+        }
+        unsafe {
+            ffi::GC_set_finalize_on_demand(self.previous_on_demand as libc::c_int);
+            SHUTDOWN = false;
+            THREAD_RUNNING.store(false, SeqCst);
+        }
+        // anything queued since the thread's last batch would
+        // otherwise wait for the next collection. If finalizers were
+        // previously run on demand, this may not be a safe point to run
+        // them, so the restored notifier is told about them instead.
+        if !self.previous_on_demand {
+            run_finalizers();
+        } else if pending_finalizers() {
+            notify();
+        }
+    }
+}
+
+impl Drop for FinalizerThread {
+    fn drop(&mut self) {
+        self.stop()
+    }
+}
+
+fn wake_finalizer_thread() {
+    unsafe {
+        let guard = THREAD_LOCK.lock();
+        WAKEUPS += 1;
+        guard.signal();
+    }
+}
+
+/// Run all queued finalizers on the finalizer thread, recording
+/// statistics about them.
+fn run_batch() {
+    let start = time::precise_time_ns();
+    let n = run_finalizers();
+    let elapsed = (time::precise_time_ns() - start) as uint;
+
+    unsafe {
+        BATCHES.fetch_add(1, SeqCst);
+        LAST_BATCH.store(n, SeqCst);
+        TOTAL_NS.fetch_add(elapsed, SeqCst);
+        if elapsed > MAX_BATCH_NS.load(SeqCst) {
+            MAX_BATCH_NS.store(elapsed, SeqCst);
+        }
+    }
+}
+
+// an object whose finalizer has run, which should have been
+// collected by now unless the finalizer resurrected it.
+struct Watch {
+    // a disappearing link to the object, holding its hidden address.
+    link: *mut ffi::GC_word,
+    gc_no: uint,
+    address: uint,
+    site: Option<(&'static str, uint)>,
+}
+
+static mut WATCH_LOCK: StaticNativeMutex = NATIVE_MUTEX_INIT;
+static mut WATCHES: *mut Vec<Watch> = 0 as *mut Vec<Watch>;
+
+// the object whose finalizer is running on this thread.
+local_data_key!(FINALIZING: uint)
+
+/// Report (through the warning handler, see `set_warn_handler`)
+/// objects that may have been resurrected by their finalizers, i.e.
+/// that are still alive two collections after their finalizer ran,
+/// for example because it stored them somewhere global. (Since the
+/// collector is conservative, an integer that happens to look like a
+/// pointer to the object can also keep it alive.)
+///
+/// This only does anything with the `debug` feature, in which case it
+/// is also called automatically before each finalizer runs. (Objects
+/// that re-register a finalizer on themselves are reported
+/// immediately by `register`.)
+pub fn check_resurrections() {
+    if !cfg!(feature = "debug") { return }
+
+    let gc_no = ::gc_count();
+    unsafe {
+        let _guard = WATCH_LOCK.lock();
+        if WATCHES.is_null() { return }
+
+        (*WATCHES).retain(|w| {
+            if *w.link == 0 {
+                // collected, as it should be.
+                ffi::GC_free(w.link as *mut libc::c_void);
+                false
+            } else if gc_no >= w.gc_no + 2 {
+                warn::warn(format!("object at {:#x}{} is still alive two collections after \
+                                    its finalizer ran (possibly resurrected)",
+                                   w.address, describe_site(w.site)).as_slice());
+                ffi::GC_unregister_disappearing_link(w.link as *mut *mut libc::c_void);
+                ffi::GC_free(w.link as *mut libc::c_void);
+                false
+            } else {
+                true
+            }
+        })
+    }
+}
+
+fn describe_site(site: Option<(&'static str, uint)>) -> String {
+    match site {
+        Some((file, line)) => format!(" (allocated at {}:{})", file, line),
+        None => String::new()
+    }
+}
+
+/// Start watching `obj` (whose finalizer has just run) for
+/// resurrection.
+unsafe fn watch_for_resurrection(obj: *mut libc::c_void) {
+    let link = ffi::GC_malloc_atomic_uncollectable(mem::size_of::<ffi::GC_word>() as libc::size_t)
+        as *mut ffi::GC_word;
+    if link.is_null() { return }
+    *link = !(obj as ffi::GC_word);
+    ffi::GC_general_register_disappearing_link(link as *mut *mut libc::c_void,
+                                               ffi::GC_base(obj));
+
+    let watch = Watch {
+        link: link,
+        gc_no: ::gc_count(),
+        address: obj as uint,
+        site: ptr_check::allocation_site(obj as *const u8),
+    };
+    let _guard = WATCH_LOCK.lock();
+    if WATCHES.is_null() {
+        WATCHES = mem::transmute(box Vec::<Watch>::new());
+    }
+    (*WATCHES).push(watch);
+}
+
+/// How a finalizer interacts with pointers between finalizable
+/// objects, and so how cycles behave.
+#[deriving(Clone, PartialEq, Show)]
+pub enum FinalizerOrder {
+    /// Finalizers run in topological order: if `a` points to `b`
+    /// (directly or indirectly), `a` is finalized first, and `b` is
+    /// only finalized once `a` has been collected. Objects in a cycle
+    /// are never finalized (or collected), and Boehm warns about
+    /// them. (`GC_register_finalizer`)
+    Ordered,
+    /// As `Ordered`, but pointers from an object to itself are
+    /// ignored, so it still gets finalized. Longer cycles behave as
+    /// with `Ordered`. (`GC_register_finalizer_ignore_self`)
+    IgnoreSelf,
+    /// Pointers from other finalizable objects are ignored, so
+    /// objects in cycles are finalized, in an unspecified order. A
+    /// finalizer may therefore see objects whose finalizers have
+    /// already run. Unless Java-style finalization is on (see
+    /// `GcConfig::java_finalization`), objects that are only
+    /// reachable from the finalizable object may already have been
+    /// reclaimed, so the finalizer can't safely resurrect it.
+    /// (`GC_register_finalizer_no_order`)
+    NoOrder,
+    /// As `NoOrder`, but the finalizer only runs once the object is
+    /// unreachable even from other finalizable objects (except via
+    /// cycles through itself), like Java's finalization.
+    /// (`GC_register_finalizer_unreachable`)
+    Unreachable,
+}
+
+/// A finalizer that was registered on an object before it was
+/// replaced or cancelled. Dropping a `RustFinalizer` frees the
+/// closure without running it.
+pub enum PreviousFinalizer<T> {
+    /// A finalizer registered with `register`.
+    RustFinalizer(proc(&mut T)),
+    /// Some other finalizer (e.g. registered from C), as the raw
+    /// procedure and client data.
+    ForeignFinalizer(ffi::GC_finalization_proc, *mut libc::c_void),
+}
+
+/// Register `f` to be run with the value of `gc` once it becomes
+/// unreachable, replacing any finalizer already registered on it
+/// (which is returned, and dropped if ignored).
+///
+/// The returned handle can be used to cancel or replace the
+/// finalizer later, e.g. when the resource it would clean up has been
+/// released explicitly.
+///
+/// Fails for zero-sized types (which are never collected), for types
+/// that need more than the default alignment (whose `Gc` may not
+/// point to the start of the allocation), and for values in an
+/// `Ephemeron` (whose finalizers are used to keep them alive).
+///
+/// Finalizers run on whichever thread calls `run_finalizers` (or
+/// allocates, if finalizers aren't run on demand), which is the
+/// finalizer thread if `spawn_finalizer_thread` is in use. In the
+/// latter case, `f` accesses the value from a thread other than the
+/// one that created it, so mustn't rely on thread-local state, and
+/// anything it shares with other threads needs to be synchronised
+/// (starting the thread is unsafe for this reason).
+pub fn register<T: 'static>(gc: &Gc<T>, order: FinalizerOrder, f: proc(&mut T))
+                            -> (FinalizerHandle<T>, Option<PreviousFinalizer<T>>) {
+    if mem::size_of::<T>() == 0 || ::is_overaligned::<T>() {
+        fail!("finalize::register: cannot register a finalizer on this type")
+    }
+
+    if cfg!(feature = "debug") &&
+        FINALIZING.get().map(|p| *p) == Some(gc.ptr as uint) {
+        warn::warn(format!("object at {:#x}{} was resurrected by its finalizer, \
+                            which registered a new one",
+                           gc.ptr as uint,
+                           describe_site(ptr_check::allocation_site(gc.ptr as *const T)))
+                   .as_slice());
+    }
+
+    let handle = FinalizerHandle { obj: HiddenPtr::hide(gc), order: order };
+    let previous = handle.set(gc, f);
+    (handle, previous)
+}
+
+/// The finalizer registered on an object by `register`, which can be
+/// cancelled or replaced.
+///
+/// This doesn't keep the object alive, so each operation also takes
+/// the object itself (which must be the one that the finalizer was
+/// registered on), to guarantee that it hasn't been collected yet.
+pub struct FinalizerHandle<T> {
+    obj: HiddenPtr<T>,
+    order: FinalizerOrder,
+}
+
+impl<T: 'static> FinalizerHandle<T> {
+    /// The order that the finalizer was registered with.
+    pub fn order(&self) -> FinalizerOrder {
+        self.order
+    }
+
+    /// Remove the finalizer from `gc`, returning it (or whatever
+    /// finalizer has since replaced it).
+    pub fn cancel(self, gc: &Gc<T>) -> Option<PreviousFinalizer<T>> {
+        self.check(gc);
+        unsafe {
+            let (old_fn, old_data) = replace_finalizer(gc.ptr as *mut libc::c_void, self.order,
+                                                       None, ptr::null_mut());
+            previous_finalizer::<T>(old_fn, old_data)
+        }
+    }
+
+    /// Replace the finalizer on `gc` with `f`, returning the old one.
+    pub fn replace(&self, gc: &Gc<T>, f: proc(&mut T)) -> Option<PreviousFinalizer<T>> {
+        self.check(gc);
+        self.set(gc, f)
+    }
+
+    fn check(&self, gc: &Gc<T>) {
+        if HiddenPtr::hide(gc) != self.obj {
+            fail!("FinalizerHandle: used with a different object to the one it was registered on")
+        }
+    }
+
+    fn set(&self, gc: &Gc<T>, f: proc(&mut T)) -> Option<PreviousFinalizer<T>> {
+        unsafe {
+            let data: *mut libc::c_void = mem::transmute(Closure::new(f));
+            let (old_fn, old_data) = replace_finalizer(gc.ptr as *mut libc::c_void, self.order,
+                                                       Some(run_finalizer), data);
+            previous_finalizer::<T>(old_fn, old_data)
+        }
+    }
+}
+
+/// Register a raw finalization procedure `f`, which is called as
+/// `f(obj, data)`, on `obj`, returning the procedure and client data
+/// that were previously registered (`None` and null if there were
+/// none). Passing `None` for `f` unregisters the finalizer.
+///
+/// The caller is responsible for freeing any client data that is
+/// returned.
+///
+/// This is unsafe because `obj` must be the start of an object
+/// allocated by this crate, and `f` must handle `data` correctly. In
+/// particular, `obj` mustn't be a value in an `Ephemeron`, since
+/// replacing its finalizer would let it be collected while it's still
+/// in the table.
+pub unsafe fn register_raw(obj: *mut libc::c_void, order: FinalizerOrder,
+                           f: Option<ffi::GC_finalization_proc>, data: *mut libc::c_void)
+                           -> (Option<ffi::GC_finalization_proc>, *mut libc::c_void) {
+    let mut old_fn: Option<ffi::GC_finalization_proc> = None;
+    let mut old_data: *mut libc::c_void = ptr::null_mut();
+    let ofn = &mut old_fn as *mut Option<ffi::GC_finalization_proc>;
+    let ocd = &mut old_data as *mut *mut libc::c_void;
+
+    let debug = ::debug_alloc();
+    match order {
+        Ordered if debug => ffi::GC_debug_register_finalizer(obj, f, data, ofn, ocd),
+        Ordered => ffi::GC_register_finalizer(obj, f, data, ofn, ocd),
+        IgnoreSelf if debug => ffi::GC_debug_register_finalizer_ignore_self(obj, f, data, ofn, ocd),
+        IgnoreSelf => ffi::GC_register_finalizer_ignore_self(obj, f, data, ofn, ocd),
+        NoOrder if debug => ffi::GC_debug_register_finalizer_no_order(obj, f, data, ofn, ocd),
+        NoOrder => ffi::GC_register_finalizer_no_order(obj, f, data, ofn, ocd),
+        Unreachable if debug => ffi::GC_debug_register_finalizer_unreachable(obj, f, data, ofn, ocd),
+        Unreachable => ffi::GC_register_finalizer_unreachable(obj, f, data, ofn, ocd),
+    }
+
+    (old_fn, old_data)
+}
+
+/// `register_raw`, but refusing to replace the finalizer that keeps a
+/// value in an `Ephemeron` alive: that is put back, and this fails.
+unsafe fn replace_finalizer(obj: *mut libc::c_void, order: FinalizerOrder,
+                            f: Option<ffi::GC_finalization_proc>, data: *mut libc::c_void)
+                            -> (Option<ffi::GC_finalization_proc>, *mut libc::c_void) {
+    let (old_fn, old_data) = register_raw(obj, order, f, data);
+    match old_fn {
+        Some(old) if collections::is_value_finalizer(old) => {
+            if collections::release_value_finalizer(old_data) {
+                // the entry had already been removed from its table.
+                (None, ptr::null_mut())
+            } else {
+                let (new_fn, new_data) = register_raw(obj, NoOrder, old_fn, old_data);
+                if new_fn.is_some() {
+                    let _: Box<Closure> = mem::transmute(new_data);
+                }
+                fail!("finalize: cannot replace the finalizer of a value in an Ephemeron")
+            }
+        }
+        _ => (old_fn, old_data)
+    }
+}
+
+/// Interpret the procedure and client data returned from
+/// `register_raw` on an object of type `T`. A finalizer that was
+/// registered with `register` through a `Gc` of some other type is
+/// dropped, and `None` returned.
+unsafe fn previous_finalizer<T: 'static>(old_fn: Option<ffi::GC_finalization_proc>,
+                                         old_data: *mut libc::c_void)
+                                         -> Option<PreviousFinalizer<T>> {
+    match old_fn {
+        None => None,
+        Some(f) if f as uint == run_finalizer as uint => {
+            let mut closure: Box<Closure> = mem::transmute(old_data);
+            closure.take::<T>().map(RustFinalizer)
+        }
+        Some(f) => Some(ForeignFinalizer(f, old_data))
+    }
+}
+
+/// The client data of the finalizers registered by `register`: a
+/// boxed `proc(&mut T)`, with how to call and drop it.
+///
+/// Erasing `T` here means that a single, non-generic `run_finalizer`
+/// is used for every type, so that finalizers can be recognised by
+/// comparing against it (monomorphised copies of a generic one would
+/// differ between crates).
+struct Closure {
+    type_id: TypeId,
+    call: unsafe fn(obj: *mut libc::c_void, f: *mut u8),
+    drop: unsafe fn(f: *mut u8),
+    // the `Box<proc(&mut T)>`, or null once it has been taken.
+    f: *mut u8,
+}
+
+impl Closure {
+    fn new<T: 'static>(f: proc(&mut T)) -> Box<Closure> {
+        box Closure {
+            type_id: TypeId::of::<T>(),
+            call: call_closure::<T>,
+            drop: drop_closure::<T>,
+            f: unsafe { mem::transmute(box f) },
+        }
+    }
+
+    /// Take the closure out, if it is for a `T`.
+    unsafe fn take<T: 'static>(&mut self) -> Option<proc(&mut T)> {
+        if self.type_id != TypeId::of::<T>() {
+            return None
+        }
+        let f: Box<proc(&mut T)> = mem::transmute(self.f);
+        self.f = ptr::null_mut();
+        Some(*f)
+    }
+}
+
+impl Drop for Closure {
+    fn drop(&mut self) {
+        if !self.f.is_null() {
+            unsafe { (self.drop)(self.f) }
+        }
+    }
+}
+
+unsafe fn call_closure<T>(obj: *mut libc::c_void, f: *mut u8) {
+    let f: Box<proc(&mut T)> = mem::transmute(f);
+    (*f)(&mut *(obj as *mut T))
+}
+
+unsafe fn drop_closure<T>(f: *mut u8) {
+    let _: Box<proc(&mut T)> = mem::transmute(f);
+}
+
+extern "C" fn run_finalizer(obj: *mut libc::c_void, data: *mut libc::c_void) {
+    unsafe {
+        let mut closure: Box<Closure> = mem::transmute(data);
+        let (call, f) = (closure.call, closure.f);
+        closure.f = ptr::null_mut();
+        drop(closure);
+        // failure can't unwind through the collector, so it's
+        // stopped here (the failure message has already been printed
+        // by then).
+        let debug = cfg!(feature = "debug");
+        if debug {
+            check_resurrections();
+            FINALIZING.replace(Some(obj as uint));
+        }
+
+        let result = unwind::try(|| call(obj, f));
+        if result.is_err() {
+            reset_unwinding();
+        }
+
+        if debug {
+            FINALIZING.replace(None);
+            watch_for_resurrection(obj);
+        }
+
+        FINALIZERS_RUN.fetch_add(1, SeqCst);
+        if result.is_err() {
+            FINALIZER_PANICS.fetch_add(1, SeqCst);
+        }
+    }
+}
+
+/// `unwind::try` leaves the task marked as unwinding after a failure,
+/// and a task that fails while unwinding aborts the whole process, so
+/// a second failing finalizer on the same thread would abort. This
+/// clears the mark once the failure has been caught.
+#[doc(hidden)]
+pub fn reset_unwinding() {
+    if Local::exists(None::<Task>) {
+        let mut task = Local::borrow(None::<Task>);
+        task.unwinder = unwind::Unwinder::new();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+    #[cfg(feature = "debug")]
+    use std::kinds::marker;
+    use ffi;
+    use testing;
+    use {Gc, GcConfig};
+    use super::{spawn_finalizer_thread, register, stats, NoOrder, Ordered, RustFinalizer};
+    use super::{pending_finalizers, run_finalizers, set_finalizer_notifier,
+                clear_finalizer_notifier};
+
+    #[test]
+    fn replace_and_cancel() {
+        testing::run(|| {
+            let gc = Gc::new(1u);
+            let (handle, previous) = register(&gc, Ordered, proc(_) {});
+            assert!(previous.is_none());
+            match handle.replace(&gc, proc(x) { *x = 2 }) {
+                Some(RustFinalizer(_)) => {}
+                _ => fail!("expected the first finalizer")
+            }
+            match handle.cancel(&gc) {
+                Some(RustFinalizer(f)) => {
+                    let mut x = 0u;
+                    f(&mut x);
+                    assert_eq!(x, 2);
+                }
+                _ => fail!("expected the replacement finalizer")
+            }
+        })
+    }
+
+    static mut FINALIZED: AtomicUint = INIT_ATOMIC_UINT;
+
+    #[inline(never)]
+    fn make_finalizable() {
+        let gc = Gc::new(7u);
+        register(&gc, NoOrder, proc(x) {
+            assert_eq!(*x, 7);
+            unsafe { FINALIZED.fetch_add(1, SeqCst); }
+        });
+    }
+
+    #[test]
+    fn finalizer_runs() {
+        testing::run(|| {
+            let before = unsafe { FINALIZED.load(SeqCst) };
+            make_finalizable();
+            testing::collect_fully();
+            assert_eq!(unsafe { FINALIZED.load(SeqCst) }, before + 1);
+        })
+    }
+
+    #[test]
+    fn finalize_on_demand() {
+        testing::run(|| {
+            // run anything left over by other tests first.
+            testing::collect_fully();
+            let on_demand = unsafe { ffi::GC_get_finalize_on_demand() != 0 };
+            GcConfig::new().finalize_on_demand(true).apply();
+            let (tx, rx) = channel();
+            let previous = set_finalizer_notifier(box move |&:| tx.send(()));
+
+            let finalized = unsafe { FINALIZED.load(SeqCst) };
+            make_finalizable();
+            testing::clear_stack();
+            ::collect();
+            assert_eq!(unsafe { FINALIZED.load(SeqCst) }, finalized);
+            assert!(pending_finalizers());
+            assert!(rx.try_recv().is_ok());
+
+            assert_eq!(run_finalizers(), 1);
+            assert_eq!(unsafe { FINALIZED.load(SeqCst) }, finalized + 1);
+            assert!(!pending_finalizers());
+
+            match previous {
+                Some(f) => { set_finalizer_notifier(f); }
+                None => { clear_finalizer_notifier(); }
+            }
+            GcConfig::new().finalize_on_demand(on_demand).apply();
+        })
+    }
+
+    // where `resurrect` stores its object, which the collector scans.
+    #[cfg(feature = "debug")]
+    static mut RESURRECTED: uint = 0;
+
+    #[inline(never)]
+    #[cfg(feature = "debug")]
+    fn resurrect() {
+        let gc = Gc::new_at(1u, "resurrect.rs\x00", 7);
+        register(&gc, NoOrder, proc(x) {
+            unsafe { RESURRECTED = x as *mut uint as uint; }
+        });
+    }
+
+    #[cfg(feature = "debug")]
+    fn warnings(rx: &Receiver<String>) -> Vec<String> {
+        let mut msgs = Vec::new();
+        loop {
+            match rx.try_recv() {
+                Ok(msg) => msgs.push(msg),
+                Err(_) => return msgs
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "debug")]
+    fn resurrection_is_reported() {
+        testing::run(|| {
+            let (tx, rx) = channel();
+            ::set_warn_handler(box move |&: msg: &str, _arg: uint| tx.send(msg.to_string()));
+            resurrect();
+            for _ in range(0u, 4) {
+                testing::collect_fully();
+            }
+            super::check_resurrections();
+            ::clear_warn_handler();
+
+            assert!(unsafe { RESURRECTED } != 0);
+            assert!(warnings(&rx).iter().any(|msg| {
+                msg.as_slice().contains("(allocated at resurrect.rs:7) is still alive") &&
+                    msg.as_slice().contains("possibly resurrected")
+            }));
+            unsafe { RESURRECTED = 0; }
+        })
+    }
+
+    #[inline(never)]
+    #[cfg(feature = "debug")]
+    fn reregister() {
+        let gc = Gc::new_at(2u, "reregister.rs\x00", 9);
+        register(&gc, NoOrder, proc(x) {
+            let gc = Gc { ptr: x as *mut uint, mark: marker::NoSend };
+            register(&gc, NoOrder, proc(_) {});
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "debug")]
+    fn reregistration_is_reported() {
+        testing::run(|| {
+            let (tx, rx) = channel();
+            ::set_warn_handler(box move |&: msg: &str, _arg: uint| tx.send(msg.to_string()));
+            reregister();
+            testing::collect_fully();
+            ::clear_warn_handler();
+
+            assert!(warnings(&rx).iter().any(|msg| {
+                msg.as_slice().contains("(allocated at reregister.rs:9) was resurrected by its \
+                                         finalizer, which registered a new one")
+            }));
+        })
+    }
+
+    #[inline(never)]
+    fn make_finalizable_signalling(tx: Sender<()>) {
+        let gc = Gc::new(7u);
+        register(&gc, NoOrder, proc(_) { tx.send(()) });
+    }
+
+    #[test]
+    fn finalizer_thread_runs_finalizers() {
+        testing::run(|| {
+            let before = stats();
+            let thread = unsafe { spawn_finalizer_thread() };
+            let (tx, rx) = channel();
+            make_finalizable_signalling(tx);
+            testing::clear_stack();
+            ::collect();
+            // the thread is woken up by the collection and runs the
+            // finalizer without anything else calling `run_finalizers`.
+            rx.recv();
+            thread.shutdown();
+
+            let after = stats();
+            assert!(after.runs > before.runs);
+            assert!(after.batches > before.batches);
+            assert!(after.total_ns > before.total_ns);
+            assert!(after.max_batch_ns <= after.total_ns);
+        })
+    }
+
+    #[inline(never)]
+    fn make_failing_finalizers() {
+        for i in range(0u, 4) {
+            let gc = Gc::new(i);
+            if i < 2 {
+                register(&gc, NoOrder, proc(_) { fail!("finalizer failed") });
+            } else {
+                register(&gc, NoOrder, proc(_) {
+                    unsafe { FINALIZED.fetch_add(1, SeqCst); }
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn failing_finalizers_are_isolated() {
+        testing::run(|| {
+            let before = stats();
+            let finalized = unsafe { FINALIZED.load(SeqCst) };
+            make_failing_finalizers();
+            // all four are queued by the same collection and run one
+            // after another on this thread, so the second failure
+            // happens after the first has been caught.
+            testing::collect_fully();
+            let after = stats();
+            assert_eq!(after.panics, before.panics + 2);
+            assert!(after.runs >= before.runs + 4);
+            assert_eq!(unsafe { FINALIZED.load(SeqCst) }, finalized + 2);
+        })
+    }
+
+    #[test]
+    fn finalizer_thread_restores_on_demand() {
+        testing::run(|| unsafe {
+            let before = ffi::GC_get_finalize_on_demand();
+            let thread = spawn_finalizer_thread();
+            assert_eq!(ffi::GC_get_finalize_on_demand(), 1);
+            thread.shutdown();
+            assert_eq!(ffi::GC_get_finalize_on_demand(), before);
+        })
+    }
+}
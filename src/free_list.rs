@@ -0,0 +1,127 @@
+//! Batched allocation of small objects.
+
+use libc;
+use std::{cmp, mem, ptr};
+use std::kinds::marker;
+
+use ffi;
+use Gc;
+
+/// Create an (initially empty) free list of pre-allocated `T`-sized
+/// objects. See `GcFreeList`.
+pub fn alloc_many<T: 'static>() -> GcFreeList<T> {
+    GcFreeList {
+        head: ptr::null_mut(),
+        mark: marker::NoSend
+    }
+}
+
+/// A thread-local list of pre-allocated objects, refilled in batches
+/// with `GC_malloc_many`.
+///
+/// Taking the allocation lock once per batch rather than once per
+/// object makes this faster than `Gc::new` for code that allocates
+/// lots of values of the same type.
+///
+/// The unused objects are only kept alive by the list itself, so it
+/// should be stored somewhere the collector scans (e.g. on the
+/// stack), not inside a non-GC heap allocation like a `Box`;
+/// otherwise they will be reclaimed while still on the list.
+pub struct GcFreeList<T> {
+    // each object on the list stores the next one in its first word
+    // (`GC_NEXT` in gc.h).
+    head: *mut *mut libc::c_void,
+    mark: marker::NoSend
+}
+
+impl<T: 'static> GcFreeList<T> {
+    /// Allocate `value`, taking an object from the list (refilling it
+    /// first if it is empty).
+    ///
+    /// Debug mode, zero-sized types and types that need more than the
+    /// default alignment always go through `Gc::new`.
+    pub fn alloc(&mut self, value: T) -> Gc<T> {
+        if ::debug_alloc() || mem::size_of::<T>() == 0 || ::is_overaligned::<T>() {
+            return Gc::new(value)
+        }
+        if self.head.is_null() {
+            self.refill()
+        }
+        unsafe {
+            let p = self.head;
+            self.head = *p as *mut *mut libc::c_void;
+            *p = ptr::null_mut();
+            Gc::init(p as *mut T, value)
+        }
+    }
+
+    /// Whether the next `alloc` will need to fetch a new batch.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.head.is_null()
+    }
+
+    fn refill(&mut self) {
+        // each object needs room for the link.
+        let size = cmp::max(mem::size_of::<T>(), mem::size_of::<*mut libc::c_void>());
+        let p = unsafe { ffi::GC_malloc_many(size as libc::size_t) };
+        if p.is_null() {
+            fail!("Could not allocate")
+        }
+        self.head = p as *mut *mut libc::c_void;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use testing;
+    use Gc;
+    use super::alloc_many;
+
+    struct Node {
+        id: uint,
+        next: Option<Gc<Node>>,
+    }
+
+    #[test]
+    fn alloc_across_refill() {
+        testing::run(|| {
+            let mut list = alloc_many::<Node>();
+            assert!(list.is_empty());
+            let mut head = list.alloc(Node { id: 0, next: None });
+            let mut n = 1u;
+            // the debug allocator doesn't use the list.
+            if !::debug_alloc() {
+                assert!(!list.is_empty());
+                while !list.is_empty() {
+                    head = list.alloc(Node { id: n, next: Some(head) });
+                    n += 1;
+                }
+            }
+            // these come from a second batch.
+            for _ in range(0u, 5) {
+                head = list.alloc(Node { id: n, next: Some(head) });
+                n += 1;
+            }
+            testing::collect_fully();
+
+            let mut addresses = Vec::new();
+            let mut expected = n;
+            let mut current = Some(head);
+            loop {
+                let gc = match current {
+                    Some(gc) => gc,
+                    None => break
+                };
+                expected -= 1;
+                assert_eq!(gc.borrow().id, expected);
+                addresses.push(gc.borrow() as *const Node as uint);
+                current = gc.borrow().next.clone();
+            }
+            assert_eq!(expected, 0);
+            addresses.sort();
+            addresses.dedup();
+            assert_eq!(addresses.len(), n);
+        })
+    }
+}
@@ -0,0 +1,114 @@
+//! Pointers that the collector can't see.
+
+use std::hash::{Hash, Writer};
+use std::kinds::marker;
+
+use ffi::GC_hidden_pointer;
+use Gc;
+
+/// The address of a GC object, stored in a form that the collector
+/// won't recognise as a pointer (`GC_HIDE_POINTER` in gc.h), and so
+/// doesn't keep the object alive.
+///
+/// This is useful for weak structures and side tables keyed by
+/// address. Hidden pointers compare (and hash) by address.
+pub struct HiddenPtr<T> {
+    bits: GC_hidden_pointer
+}
+
+#[inline]
+fn hide_bits<T>(p: *const T) -> GC_hidden_pointer {
+    !(p as GC_hidden_pointer)
+}
+
+impl<T: 'static> HiddenPtr<T> {
+    /// Hide the address of `gc`.
+    #[inline]
+    pub fn hide(gc: &Gc<T>) -> HiddenPtr<T> {
+        HiddenPtr::from_raw(gc.ptr as *const T)
+    }
+
+    /// Hide an arbitrary address.
+    #[inline]
+    pub fn from_raw(ptr: *const T) -> HiddenPtr<T> {
+        HiddenPtr { bits: hide_bits(ptr) }
+    }
+
+    /// The hidden address.
+    #[inline]
+    pub fn as_raw(&self) -> *const T {
+        (!self.bits) as *const T
+    }
+
+    /// Turn this back into a `Gc`.
+    ///
+    /// This is unsafe because the object may have been collected (and
+    /// its memory reused) since it was hidden, as the hidden pointer
+    /// doesn't keep it alive. Use with a disappearing link or a
+    /// finalizer to know when that has happened.
+    #[inline]
+    pub unsafe fn reveal(&self) -> Gc<T> {
+        Gc { ptr: (!self.bits) as *mut T, mark: marker::NoSend }
+    }
+}
+
+impl<T> Clone for HiddenPtr<T> {
+    fn clone(&self) -> HiddenPtr<T> {
+        HiddenPtr { bits: self.bits }
+    }
+}
+
+impl<T> PartialEq for HiddenPtr<T> {
+    fn eq(&self, other: &HiddenPtr<T>) -> bool {
+        self.bits == other.bits
+    }
+}
+
+impl<T> Eq for HiddenPtr<T> {}
+
+impl<T, S: Writer> Hash<S> for HiddenPtr<T> {
+    fn hash(&self, state: &mut S) {
+        self.bits.hash(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::hash::hash;
+    use {Gc, GcWeak};
+    use testing;
+    use super::HiddenPtr;
+
+    #[test]
+    fn round_trip() {
+        testing::run(|| {
+            let gc = Gc::new(7u);
+            let hidden = HiddenPtr::hide(&gc);
+            assert_eq!(hidden.as_raw(), gc.as_ptr() as *const uint);
+            let revealed = unsafe { hidden.reveal() };
+            assert_eq!(revealed.as_ptr(), gc.as_ptr());
+            assert_eq!(*revealed.borrow(), 7);
+
+            let again = HiddenPtr::from_raw(gc.as_ptr() as *const uint);
+            assert!(hidden == again);
+            assert_eq!(hash(&hidden), hash(&again));
+            assert!(hidden != HiddenPtr::hide(&Gc::new(7u)));
+        })
+    }
+
+    #[inline(never)]
+    fn hide_only() -> (HiddenPtr<uint>, GcWeak<uint>) {
+        let gc = Gc::new(3u);
+        (HiddenPtr::hide(&gc), gc.downgrade())
+    }
+
+    #[test]
+    fn does_not_keep_alive() {
+        testing::run(|| {
+            let (hidden, weak) = hide_only();
+            testing::collect_fully();
+            assert!(!weak.is_alive());
+            drop(hidden);
+        })
+    }
+}
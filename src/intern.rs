@@ -0,0 +1,169 @@
+//! Interned strings in the GC heap.
+
+use libc;
+use std::{fmt, mem, raw};
+use std::hash::{Hash, Writer};
+use std::kinds::marker;
+
+use ffi;
+use collections::WeakValueMap;
+use Gc;
+
+/// An interned string, allocated in the GC heap by an `Interner`.
+///
+/// Symbols from the same interner with the same contents are the
+/// same object, so they are compared (and hashed) by address. A
+/// symbol is reclaimed once nothing references it, after which
+/// interning the same string creates a new one.
+///
+/// This plays the role of a `Gc<str>`, which can't be written with
+/// this compiler, since `Gc` only holds sized types.
+pub struct Symbol {
+    ptr: *const libc::c_char,
+    len: uint,
+    mark: marker::NoSend
+}
+
+impl Symbol {
+    #[inline]
+    pub fn as_str<'a>(&'a self) -> &'a str {
+        unsafe {
+            mem::transmute(raw::Slice { data: self.ptr as *const u8, len: self.len })
+        }
+    }
+
+    /// The nul-terminated contents, e.g. for passing to C.
+    #[inline]
+    pub fn as_ptr(&self) -> *const libc::c_char {
+        self.ptr
+    }
+}
+
+impl Clone for Symbol {
+    fn clone(&self) -> Symbol {
+        Symbol { ptr: self.ptr, len: self.len, mark: marker::NoSend }
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl Eq for Symbol {}
+
+impl<S: Writer> Hash<S> for Symbol {
+    fn hash(&self, state: &mut S) {
+        (self.ptr as uint).hash(state)
+    }
+}
+
+impl fmt::Show for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+/// A table of interned strings, which doesn't keep them alive: each
+/// is stored as a weak reference (a disappearing link) to a copy made
+/// with `GC_strdup`.
+pub struct Interner {
+    symbols: WeakValueMap<String, libc::c_char>,
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner { symbols: WeakValueMap::new() }
+    }
+
+    /// The symbol for `s`, creating it if it doesn't exist (or has
+    /// been collected).
+    ///
+    /// Fails if `s` contains a nul byte.
+    pub fn intern(&mut self, s: &str) -> Symbol {
+        let key = s.to_string();
+        let ptr = match self.symbols.get(&key) {
+            Some(gc) => gc.ptr as *const libc::c_char,
+            None => {
+                let ptr = strdup(s);
+                self.symbols.insert(key, &Gc { ptr: ptr, mark: marker::NoSend });
+                ptr as *const libc::c_char
+            }
+        };
+        Symbol { ptr: ptr, len: s.len(), mark: marker::NoSend }
+    }
+
+    /// The number of symbols in the table, including any that have
+    /// been collected but not yet purged.
+    pub fn len(&self) -> uint {
+        self.symbols.len()
+    }
+
+    /// Remove the entries for all the symbols that have been
+    /// collected, returning how many there were.
+    pub fn purge(&mut self) -> uint {
+        self.symbols.purge()
+    }
+}
+
+fn strdup(s: &str) -> *mut libc::c_char {
+    let p = s.with_c_str(|c| unsafe {
+        if ::debug_alloc() {
+            ffi::GC_debug_strdup(c, b"Symbol\x00".as_ptr() as *const libc::c_char, 0)
+        } else {
+            ffi::GC_strdup(c)
+        }
+    });
+    if p.is_null() {
+        fail!("Could not allocate")
+    }
+    p
+}
+
+#[cfg(test)]
+mod test {
+    use testing;
+    use super::Interner;
+
+    #[test]
+    fn same_string_same_symbol() {
+        testing::run(|| {
+            let mut interner = Interner::new();
+            let a = interner.intern("x");
+            let b = interner.intern("x");
+            assert!(a == b);
+            assert_eq!(a.as_ptr(), b.as_ptr());
+            assert_eq!(a.as_str(), "x");
+
+            let c = interner.intern("y");
+            assert!(a != c);
+            assert_eq!(c.as_str(), "y");
+            assert_eq!(interner.len(), 2);
+        })
+    }
+
+    #[inline(never)]
+    fn intern_and_drop(interner: &mut Interner) {
+        let a = interner.intern("x");
+        let b = interner.intern("x");
+        assert!(a == b);
+    }
+
+    #[test]
+    fn unused_symbols_are_collected() {
+        testing::run(|| {
+            let mut interner = Interner::new();
+            intern_and_drop(&mut interner);
+            testing::collect_fully();
+            assert_eq!(interner.purge(), 1);
+            assert_eq!(interner.len(), 0);
+
+            // so this makes a new symbol.
+            let x = interner.intern("x");
+            assert_eq!(x.as_str(), "x");
+            assert_eq!(interner.len(), 1);
+            assert!(x == interner.intern("x"));
+        })
+    }
+}
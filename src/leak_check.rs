@@ -0,0 +1,221 @@
+//! Using the collector as a leak detector.
+//!
+//! In find-leak mode, Boehm doesn't reclaim unreachable objects, but
+//! instead reports them as leaks: this is useful for checking code
+//! that frees its allocations manually (e.g. with `Gc::free` or
+//! `GcBox`).
+//!
+//! ```rust,ignore
+//! boehm::leak_check::enable();
+//! boehm::init();
+//!
+//! run_the_code_under_test();
+//!
+//! assert!(boehm::leak_check::check().is_empty());
+//! ```
+
+use libc;
+use std::fmt;
+use std::num;
+use std::rt;
+use std::sync::atomics::{AtomicBool, INIT_ATOMIC_BOOL, SeqCst};
+
+use ffi;
+use capture;
+
+static mut ENABLED: AtomicBool = INIT_ATOMIC_BOOL;
+
+/// Switch the collector into find-leak mode.
+///
+/// This must be called before `init`, and before anything has been
+/// allocated, and fails otherwise. It also makes all allocations go
+/// through the debug allocator (and so `GC_debug_free`), so that
+/// leaks are reported with their allocation sites (see `gc_new!`);
+/// objects allocated earlier would be freed with the wrong function.
+pub fn enable() {
+    unsafe {
+        if ffi::GC_is_init_called() != 0 {
+            fail!("leak_check::enable: the collector has already been initialised")
+        }
+        ffi::GC_set_find_leak(1);
+        ENABLED.store(true, SeqCst);
+    }
+}
+
+/// Whether `enable` has been called.
+pub fn is_enabled() -> bool {
+    unsafe { ENABLED.load(SeqCst) }
+}
+
+/// A leaked allocation, i.e. one that became unreachable without
+/// being freed.
+#[deriving(Clone, PartialEq)]
+pub struct LeakReport {
+    /// The address of the object.
+    pub address: uint,
+    /// The size of the object in bytes, if known.
+    pub size: Option<uint>,
+    /// The file that allocated the object, if known.
+    pub file: Option<String>,
+    /// The line that allocated the object, if known.
+    pub line: Option<uint>,
+}
+
+impl fmt::Show for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "leaked object at {:#x}", self.address));
+        match self.size {
+            Some(sz) => try!(write!(f, " ({} bytes)", sz)),
+            None => {}
+        }
+        match (&self.file, self.line) {
+            (&Some(ref file), Some(line)) => write!(f, ", allocated at {}:{}", file, line),
+            _ => Ok(())
+        }
+    }
+}
+
+/// Run a full collection and return every leaked object that it
+/// found. (Boehm frees the objects after reporting them, so each leak
+/// is only reported once.)
+pub fn check() -> Vec<LeakReport> {
+    let output = capture::capture_fd(libc::STDERR_FILENO, || unsafe {
+        ffi::GC_gcollect();
+    });
+    parse_leaks(output.as_slice())
+}
+
+/// Extract the leaks from the collector's stderr output. Each batch of
+/// leaks is introduced by a `Found N leaked objects:` header, followed
+/// by a description of each object; anything else (e.g. warnings, or
+/// output from other threads) is ignored.
+fn parse_leaks(output: &str) -> Vec<LeakReport> {
+    let mut leaks = Vec::new();
+    let mut remaining = 0u;
+    for line in output.lines() {
+        match parse_header(line) {
+            Some(n) => {
+                remaining = n;
+                continue
+            }
+            None => {}
+        }
+        if remaining > 0 {
+            match parse_leak(line) {
+                Some(leak) => {
+                    leaks.push(leak);
+                    remaining -= 1;
+                }
+                None => {}
+            }
+        }
+    }
+    leaks
+}
+
+/// Parse the `Found N leaked objects:` line that precedes a batch of
+/// leak reports, returning `N`.
+fn parse_header(line: &str) -> Option<uint> {
+    let line = line.trim();
+    if line.starts_with("Found ") && line.ends_with(" leaked objects:") {
+        from_str(line.slice("Found ".len(), line.len() - " leaked objects:".len()))
+    } else {
+        None
+    }
+}
+
+/// Run `check` when the program exits, printing any leaks to stderr.
+pub fn check_at_exit() {
+    rt::at_exit(proc() {
+        let leaks = check();
+        if !leaks.is_empty() {
+            let mut stderr = ::std::io::stderr();
+            let _ = writeln!(stderr, "boehm: found {} leaked objects:", leaks.len());
+            for leak in leaks.iter() {
+                let _ = writeln!(stderr, "  {}", leak);
+            }
+        }
+    })
+}
+
+/// Parse a single line of Boehm's leak output, which is either
+/// `0x1234 (file.rs:10, sz=16, NORMAL)` for debug allocations, or
+/// `object at 0x1234 of appr. 16 bytes` for normal ones.
+fn parse_leak(line: &str) -> Option<LeakReport> {
+    let line = line.trim();
+    if !line.starts_with("0x") && !line.starts_with("object at ") {
+        return None
+    }
+    let start = match line.find_str("0x") {
+        Some(i) => i + 2,
+        None => return None
+    };
+    let hex = line.slice_from(start);
+    let hex = hex.slice_to(hex.find(|c: char| !c.is_digit_radix(16)).unwrap_or(hex.len()));
+    let address = match num::from_str_radix::<uint>(hex, 16) {
+        Some(a) => a,
+        None => return None
+    };
+
+    let mut report = LeakReport { address: address, size: None, file: None, line: None };
+
+    match (line.find('('), line.find_str(", sz=")) {
+        (Some(open), Some(sz)) if open < sz => {
+            let site = line.slice(open + 1, sz);
+            match site.rfind(':') {
+                Some(colon) => {
+                    report.file = Some(site.slice_to(colon).to_string());
+                    report.line = from_str(site.slice_from(colon + 1));
+                }
+                None => {}
+            }
+            let rest = line.slice_from(sz + ", sz=".len());
+            report.size = from_str(rest.slice_to(rest.find(',').unwrap_or(rest.len())));
+        }
+        _ => {
+            match line.find_str("of appr. ") {
+                Some(i) => {
+                    report.size = from_str(line.slice_from(i + "of appr. ".len())
+                                               .words().next().unwrap_or(""));
+                }
+                None => {}
+            }
+        }
+    }
+
+    Some(report)
+}
+
+#[cfg(test)]
+mod test {
+    use testing;
+    use super::{enable, is_enabled, parse_leaks, LeakReport};
+
+    #[test]
+    fn parse_leak_output() {
+        let output = "GC Warning: Repeated allocation of very large block (appr. size 1052672):\n\
+                      \tMay lead to memory leak and poor performance.\n\
+                      Found 2 leaked objects:\n\
+                      0x7f0e2c5c1fe0 (src/main.rs:10, sz=16, NORMAL)\n\
+                      object at 0x7f0e2c5c2000 of appr. 32 bytes\n\
+                      0x7f0e2c5c3000 (src/main.rs:99, sz=8, NORMAL)\n";
+        assert_eq!(parse_leaks(output),
+                   vec![LeakReport { address: 0x7f0e2c5c1fe0, size: Some(16),
+                                     file: Some("src/main.rs".to_string()), line: Some(10) },
+                        LeakReport { address: 0x7f0e2c5c2000, size: Some(32),
+                                     file: None, line: None }]);
+    }
+
+    #[test]
+    fn no_header_no_leaks() {
+        assert!(parse_leaks("0x1234 (src/main.rs:10, sz=16, NORMAL)\n").is_empty());
+    }
+
+    #[test]
+    fn enable_after_init() {
+        testing::run(|| {
+            assert!(::std::task::try(proc() { enable() }).is_err());
+            assert!(!is_enabled());
+        })
+    }
+}
@@ -1,16 +1,75 @@
 #![crate_type="rlib"]
-#![feature(globs, macro_rules)]
+#![feature(globs, macro_rules, phase, simd, unsafe_destructor, unboxed_closures)]
 
 extern crate libc;
-use std::mem;
+extern crate time;
+#[cfg(feature = "log")]
+#[phase(plugin, link)]
+extern crate log;
+use std::{mem, ptr};
 use std::kinds::marker;
 use std::intrinsics;
 
+/// Allocate a `Gc` with `Gc::new_at`, recording the location of the
+/// macro invocation as the allocation site.
+///
+/// With the `debug` feature enabled, this makes `debug_dump` and leak
+/// reports point at the code that made the allocation.
+/// `allocation_site` can only recognise sites in a limited number of
+/// distinct files (see there).
+///
+/// ```rust,ignore
+/// let x = gc_new!(1u);
+/// ```
+#[macro_export]
+macro_rules! gc_new {
+    ($e:expr) => {
+        ::boehm::Gc::new_at($e, concat!(file!(), "\x00"), line!())
+    }
+}
+
+/// Allocate a `GcTracing` with `GcTracing::new_at`, recording the
+/// location of the macro invocation as the allocation site (see
+/// `gc_new!`).
+#[macro_export]
+macro_rules! gc_tracing_new {
+    ($e:expr) => {
+        ::boehm::tracing::GcTracing::new_at($e, concat!(file!(), "\x00"), line!())
+    }
+}
+
 #[allow(dead_code)]
 #[allow(non_camel_case_types)]
 pub mod ffi;
 
 pub mod tracing;
+pub mod leak_check;
+pub mod dump;
+pub mod finalize;
+pub mod collections;
+pub mod intern;
+
+pub use config::{GcConfig, large_object_threshold};
+pub use dump::{dump_to_string, heap_dump, HeapDump};
+pub use warn::{set_warn_handler, clear_warn_handler, ignore_warnings, WarnHandler};
+pub use ptr_check::{is_heap_ptr, base, allocation_size, allocation_site, same_object,
+                    is_visible, PointerError};
+pub use free_list::{alloc_many, GcFreeList};
+pub use cursor::GcCursor;
+pub use hidden::HiddenPtr;
+pub use weak::GcWeak;
+pub use finalize::{pending_finalizers, run_finalizers, set_finalizer_notifier,
+                   FinalizerNotifier};
+mod capture;
+mod config;
+mod cursor;
+mod free_list;
+mod hidden;
+mod ptr_check;
+mod warn;
+mod weak;
+#[cfg(test)]
+mod testing;
 
 /// Initialise the GC. This should be called before using any other
 /// functions and on the main thread for maximum portability (some
@@ -19,26 +78,107 @@ pub mod tracing;
 /// FIXME: initialise automagically somehow
 /// FIXME: this should be doing the full equivalent of the GC_INIT()
 /// macro.
+///
+/// With the `log` feature enabled, this also sends the collector's
+/// warnings to `warn!`, unless `set_warn_handler` or
+/// `ignore_warnings` has already been called.
 pub fn init() {
     unsafe { ffi::GC_init(); }
+    install_log_warnings();
 }
 
+#[cfg(feature = "log")]
+fn install_log_warnings() {
+    if !warn::handler_set() {
+        set_warn_handler(box |&: msg: &str, arg: uint| warn::log_warning(msg, arg))
+    }
+}
+#[cfg(not(feature = "log"))]
+fn install_log_warnings() {}
+
 /// Number of bytes in the garbage collection heap.
 pub fn heap_size() -> uint {
     unsafe { ffi::GC_get_heap_size() as uint }
 }
 
+/// Number of bytes in the heap that have been returned to the
+/// operating system (and so aren't counted by the process's resident
+/// set), but are still reserved by the collector.
+pub fn unmapped_bytes() -> uint {
+    unsafe { ffi::GC_get_unmapped_bytes() as uint }
+}
+
+/// Number of bytes in the heap that are currently free.
+pub fn free_bytes() -> uint {
+    unsafe { ffi::GC_get_free_bytes() as uint }
+}
+
 /// Force a garbage collection.
 pub fn collect() {
     unsafe { ffi::GC_gcollect(); }
 }
 
-/// Dump some debugging/diagnostic information to stdout.
+/// Force a garbage collection, and then return as much of the free
+/// heap as possible to the operating system.
+///
+/// This only has an effect if the GC was built with unmapping
+/// support (`USE_MUNMAP`).
+pub fn collect_and_unmap() {
+    unsafe { ffi::GC_gcollect_and_unmap(); }
+}
+
+/// Dump some debugging/diagnostic information to stdout. See
+/// `dump_to_string` and `heap_dump` for capturing it instead.
 pub fn debug_dump() {
     unsafe { ffi::GC_dump(); }
 }
 
+/// The number of collections that have been performed so far.
+pub fn gc_count() -> uint {
+    unsafe { ffi::GC_get_gc_no() as uint }
+}
+
+/// Disable garbage collection until the returned guard is dropped.
+///
+/// This is for critical sections that temporarily store GC pointers
+/// somewhere the collector can't see. Boehm counts disables, so these
+/// nest: collection only resumes once every guard has been dropped.
+pub fn disable() -> NoGcGuard {
+    unsafe { ffi::GC_disable(); }
+    NoGcGuard { _priv: () }
+}
+
+/// Whether garbage collection is currently disabled (e.g. by a
+/// `NoGcGuard`).
+pub fn is_disabled() -> bool {
+    unsafe { ffi::GC_is_disabled() != 0 }
+}
+
+/// Keeps garbage collection disabled while it is alive. Created by
+/// `disable`.
+pub struct NoGcGuard {
+    _priv: ()
+}
+
+impl NoGcGuard {
+    /// Whether garbage collection is currently disabled, which is
+    /// always the case while a guard exists.
+    #[inline]
+    pub fn is_disabled(&self) -> bool {
+        is_disabled()
+    }
+}
+
+impl Drop for NoGcGuard {
+    fn drop(&mut self) {
+        unsafe { ffi::GC_enable(); }
+    }
+}
+
 /// A garbage collected pointer.
+///
+/// Zero-sized values don't allocate at all: every `Gc` of such a type
+/// points at the same dangling (but aligned) address.
 #[deriving(Clone)]
 #[allow(raw_pointer_deriving)]
 pub struct Gc<T> {
@@ -47,25 +187,656 @@ pub struct Gc<T> {
 }
 
 impl<T: 'static> Gc<T> {
+    /// Allocate a new `Gc`. Values at least as large as
+    /// `large_object_threshold()` bytes are allocated as with
+    /// `new_large`.
     pub fn new(value: T) -> Gc<T> {
+        Gc::new_at(value, "Gc\x00", 0)
+    }
+
+    /// Allocate a new `Gc`, recording `file` and `line` as its
+    /// allocation site when the debug allocator is in use (the `debug`
+    /// feature), so that it shows up in `debug_dump` and leak
+    /// reports. `file` must be nul-terminated.
+    ///
+    /// This is normally called via `gc_new!`, which fills in the
+    /// location automatically.
+    pub fn new_at(value: T, file: &'static str, line: uint) -> Gc<T> {
+        let size = mem::size_of::<T>();
+        if size >= large_object_threshold() {
+            return Gc::new_large_at(value, file, line)
+        }
         unsafe {
-            let size = mem::size_of::<T>() as libc::size_t;
-            let p = if cfg!(debug) {
-                ffi::GC_debug_malloc(size, b"Gc\x00".as_ptr() as *const i8, 0)
+            let size = size as libc::size_t;
+            let site = if debug_alloc() { debug_site(file, line) } else { ptr::null() };
+            let p = if mem::size_of::<T>() == 0 {
+                zst_sentinel::<T>()
+            } else if is_overaligned::<T>() {
+                memalign::<T>()
+            } else if debug_alloc() {
+                ffi::GC_debug_malloc(size, site, line as libc::c_int)
             } else {
                 ffi::GC_malloc(size)
-            } as *mut T;
-            if p.is_null() {
-                fail!("Could not allocate")
-            }
-            intrinsics::move_val_init(&mut *p, value);
-            Gc { ptr: p, mark: marker::NoSend }
+            };
+            Gc::init(p as *mut T, value)
         }
     }
 
+    /// Allocate a new `Gc` for a large value, telling the collector
+    /// that only pointers to (near) the start of the allocation need
+    /// to be recognised.
+    ///
+    /// This avoids large blocks being retained by random integers that
+    /// happen to point into their middle, and the "Repeated allocation
+    /// of very large block" warnings that Boehm emits for them. It is
+    /// safe since a `Gc` always points at the start of its allocation,
+    /// and a `GcRef` projected from it keeps hold of that pointer.
+    pub fn new_large(value: T) -> Gc<T> {
+        Gc::new_large_at(value, "Gc\x00", 0)
+    }
+
+    /// `new_large`, with an allocation site as for `new_at`.
+    pub fn new_large_at(value: T, file: &'static str, line: uint) -> Gc<T> {
+        unsafe {
+            let size = mem::size_of::<T>() as libc::size_t;
+            let site = if debug_alloc() { debug_site(file, line) } else { ptr::null() };
+            let p = if mem::size_of::<T>() == 0 {
+                zst_sentinel::<T>()
+            } else if is_overaligned::<T>() {
+                memalign::<T>()
+            } else if debug_alloc() {
+                ffi::GC_debug_malloc_ignore_off_page(size, site, line as libc::c_int)
+            } else {
+                ffi::GC_malloc_ignore_off_page(size)
+            };
+            Gc::init(p as *mut T, value)
+        }
+    }
+
+    unsafe fn init(p: *mut T, value: T) -> Gc<T> {
+        if p.is_null() {
+            fail!("Could not allocate")
+        }
+        intrinsics::move_val_init(&mut *p, value);
+        Gc { ptr: p, mark: marker::NoSend }
+    }
+
     pub fn borrow<'r>(&'r self) -> &'r T {
         unsafe {
             &*self.ptr
         }
     }
+
+    /// Create a reference to part of the value (e.g. a field), which
+    /// keeps the whole object alive.
+    ///
+    /// Fails if `f` returns a reference to something outside the
+    /// object.
+    pub fn project<U>(&self, f: <'a>|&'a T| -> &'a U) -> GcRef<U> {
+        let p = f(self.borrow()) as *const U;
+        if (p as uint) < (self.ptr as uint) ||
+            p as uint - self.ptr as uint + mem::size_of::<U>() > mem::size_of::<T>() {
+            fail!("Gc::project: the projection must point into the object")
+        }
+        GcRef { parent: self.ptr as *const u8, ptr: p, mark: marker::NoSend }
+    }
+
+    /// A weak reference to this object, which doesn't keep it alive.
+    pub fn downgrade(&self) -> GcWeak<T> {
+        GcWeak::new(self)
+    }
+
+    /// The size (in bytes) of the underlying allocation, which may
+    /// be more than `size_of::<T>()`. This is 0 for zero-sized types.
+    pub fn allocation_size(&self) -> uint {
+        if mem::size_of::<T>() == 0 {
+            0
+        } else {
+            allocation_size(self.ptr as *const T).unwrap()
+        }
+    }
+
+    /// Return the allocation behind `this` to the collector
+    /// immediately, rather than waiting for it to be found
+    /// unreachable. The value's destructor is not run.
+    ///
+    /// This is unsafe because any other copies of `this` (or
+    /// pointers into it) are left dangling.
+    pub unsafe fn free(this: Gc<T>) {
+        free_ptr(this.ptr)
+    }
+}
+
+/// A reference to part of a garbage collected object, created by
+/// `Gc::project`. This keeps the whole object alive.
+///
+/// The collector doesn't always recognise a pointer into the middle of
+/// an object (e.g. far into a large object, or with interior pointers
+/// disabled), so this holds on to the parent's pointer too.
+#[deriving(Clone)]
+#[allow(raw_pointer_deriving)]
+pub struct GcRef<T> {
+    parent: *const u8,
+    ptr: *const T,
+    mark: marker::NoSend
+}
+
+impl<T> GcRef<T> {
+    #[inline]
+    pub fn borrow<'r>(&'r self) -> &'r T {
+        unsafe {
+            &*self.ptr
+        }
+    }
+}
+
+/// A uniquely owned garbage collected value.
+///
+/// Since there are no other references to it, a `GcBox` can be
+/// mutated directly, and its allocation is handed straight back to
+/// the collector (after running the destructor of the contents) when
+/// it is dropped, rather than waiting for a collection to find
+/// it. This makes it useful for temporaries in hot loops. It can also
+/// be converted into a normal shared `Gc` with `into_gc`.
+pub struct GcBox<T> {
+    ptr: *mut T,
+    mark: marker::NoSend
+}
+
+impl<T: 'static> GcBox<T> {
+    pub fn new(value: T) -> GcBox<T> {
+        let Gc { ptr, mark } = Gc::new(value);
+        GcBox { ptr: ptr, mark: mark }
+    }
+
+    #[inline]
+    pub fn borrow<'r>(&'r self) -> &'r T {
+        unsafe {
+            &*self.ptr
+        }
+    }
+
+    #[inline]
+    pub fn borrow_mut<'r>(&'r mut self) -> &'r mut T {
+        unsafe {
+            &mut *self.ptr
+        }
+    }
+
+    /// Give up unique ownership, turning this into a shared `Gc`. The
+    /// allocation will then only be reclaimed by a collection.
+    pub fn into_gc(self) -> Gc<T> {
+        let ptr = self.ptr;
+        unsafe { mem::forget(self); }
+        Gc { ptr: ptr, mark: marker::NoSend }
+    }
+
+    /// Move the value out, freeing the allocation.
+    pub fn into_inner(self) -> T {
+        unsafe {
+            let value = ptr::read(&*self.ptr);
+            free_ptr(self.ptr);
+            mem::forget(self);
+            value
+        }
+    }
+}
+
+#[unsafe_destructor]
+impl<T> Drop for GcBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            drop(ptr::read(&*self.ptr));
+            free_ptr(self.ptr);
+        }
+    }
+}
+
+/// A value allocated in uncollectable memory.
+///
+/// The allocation is never reclaimed by the collector, but is scanned
+/// by it, and so acts as an explicit root: everything it references is
+/// kept alive. This makes it suitable for things like the userdata of
+/// callbacks given to C libraries, which the collector can't see.
+///
+/// The value is dropped and the memory freed when the `GcPinned` is
+/// dropped.
+pub struct GcPinned<T> {
+    ptr: *mut T,
+    mark: marker::NoSend
+}
+
+impl<T: 'static> GcPinned<T> {
+    /// Pin `value` in memory that is scanned for pointers.
+    pub fn new(value: T) -> GcPinned<T> {
+        unsafe {
+            let size = mem::size_of::<T>() as libc::size_t;
+            let p = if mem::size_of::<T>() == 0 {
+                zst_sentinel::<T>()
+            } else if is_overaligned::<T>() {
+                // there's no aligned uncollectable allocator, so
+                // over-allocate and align by hand.
+                align_up::<T>(ffi::GC_malloc_uncollectable(overaligned_size::<T>()))
+            } else if debug_alloc() {
+                ffi::GC_debug_malloc_uncollectable(size, b"GcPinned\x00".as_ptr() as *const i8, 0)
+            } else {
+                ffi::GC_malloc_uncollectable(size)
+            };
+            GcPinned::init(p as *mut T, value)
+        }
+    }
+
+    /// Pin `value` in memory that is *not* scanned for pointers.
+    ///
+    /// This is cheaper, but is unsafe because any GC pointers stored
+    /// in `value` will not keep their referents alive.
+    pub unsafe fn new_atomic(value: T) -> GcPinned<T> {
+        let size = mem::size_of::<T>() as libc::size_t;
+        let p = if mem::size_of::<T>() == 0 {
+            zst_sentinel::<T>()
+        } else if is_overaligned::<T>() {
+            align_up::<T>(ffi::GC_malloc_atomic_uncollectable(overaligned_size::<T>()))
+        } else if debug_alloc() {
+            ffi::GC_debug_malloc_atomic_uncollectable(size, b"GcPinned\x00".as_ptr() as *const i8, 0)
+        } else {
+            ffi::GC_malloc_atomic_uncollectable(size)
+        };
+        GcPinned::init(p as *mut T, value)
+    }
+
+    unsafe fn init(p: *mut T, value: T) -> GcPinned<T> {
+        if p.is_null() {
+            fail!("Could not allocate")
+        }
+        intrinsics::move_val_init(&mut *p, value);
+        GcPinned { ptr: p, mark: marker::NoSend }
+    }
+
+    #[inline]
+    pub fn borrow<'r>(&'r self) -> &'r T {
+        unsafe {
+            &*self.ptr
+        }
+    }
+
+    #[inline]
+    pub fn borrow_mut<'r>(&'r mut self) -> &'r mut T {
+        unsafe {
+            &mut *self.ptr
+        }
+    }
+
+    /// Move the value out, freeing the allocation.
+    pub fn into_inner(self) -> T {
+        unsafe {
+            let value = ptr::read(&*self.ptr);
+            free_ptr(self.ptr);
+            mem::forget(self);
+            value
+        }
+    }
+
+    /// The address of the pinned value, which stays valid for as long
+    /// as `self` is alive.
+    #[inline]
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr
+    }
+}
+
+#[unsafe_destructor]
+impl<T> Drop for GcPinned<T> {
+    fn drop(&mut self) {
+        unsafe {
+            drop(ptr::read(&*self.ptr));
+            free_ptr(self.ptr);
+        }
+    }
+}
+
+/// Whether allocations should go through Boehm's debug allocator,
+/// which records allocation sites and checks for some kinds of heap
+/// corruption. This is always the case in leak checking mode.
+#[inline]
+fn debug_alloc() -> bool {
+    cfg!(feature = "debug") || leak_check::is_enabled()
+}
+
+/// Convert an allocation site file name (as passed to `Gc::new_at`)
+/// into something that the debug allocator can store, and remember it
+/// for `allocation_site`. This is done for every allocation in debug
+/// mode, even those that don't use the debug allocator, so that a bad
+/// `file` is always caught.
+///
+/// Sites with line 0 (the defaults used by `Gc::new` and the like)
+/// are never reported, so aren't remembered, to leave room for real
+/// ones.
+#[inline]
+fn debug_site(file: &'static str, line: uint) -> *const libc::c_char {
+    assert!(file.ends_with("\x00"), "allocation site file name must be nul-terminated");
+    if line != 0 {
+        ptr_check::record_file(file);
+    }
+    file.as_ptr() as *const libc::c_char
+}
+
+/// The alignment that Boehm guarantees for every allocation (its
+/// "granule" size).
+#[inline]
+fn gc_alignment() -> uint {
+    2 * mem::size_of::<ffi::GC_word>()
+}
+
+/// Whether `T` needs a stricter alignment than the allocator gives by
+/// default, e.g. SIMD types.
+#[inline]
+fn is_overaligned<T>() -> bool {
+    mem::min_align_of::<T>() > gc_alignment()
+}
+
+/// Allocate scanned memory for a `T`, respecting its alignment.
+///
+/// The result may not be the base of the underlying allocation, and
+/// isn't a debug allocation, even in debug mode.
+unsafe fn memalign<T>() -> *mut libc::c_void {
+    memalign_bytes(mem::min_align_of::<T>(), mem::size_of::<T>())
+}
+
+#[inline]
+unsafe fn memalign_bytes(align: uint, size: uint) -> *mut libc::c_void {
+    ffi::GC_memalign(align as libc::size_t, size as libc::size_t)
+}
+
+/// The number of bytes to allocate to be able to fit a `T` at a
+/// correctly aligned address with `align_up`.
+#[inline]
+fn overaligned_size<T>() -> libc::size_t {
+    (mem::size_of::<T>() + mem::min_align_of::<T>() - 1) as libc::size_t
+}
+
+/// Round `p` up to the alignment of `T` (leaving null alone).
+#[inline]
+fn align_up<T>(p: *mut libc::c_void) -> *mut libc::c_void {
+    align_up_bytes(p, mem::min_align_of::<T>())
+}
+
+#[inline]
+fn align_up_bytes(p: *mut libc::c_void, align: uint) -> *mut libc::c_void {
+    if p.is_null() {
+        p
+    } else {
+        ((p as uint + align - 1) & !(align - 1)) as *mut libc::c_void
+    }
+}
+
+/// The "allocation" used for zero-sized types: a non-null, suitably
+/// aligned address that doesn't point into the GC heap. It is shared
+/// by every value of the type, and is never traced or freed.
+#[inline]
+fn zst_sentinel<T>() -> *mut libc::c_void {
+    mem::min_align_of::<T>() as *mut libc::c_void
+}
+
+/// Free an allocation made by `Gc::new` (or similar), using the
+/// deallocator that matches the allocator.
+unsafe fn free_ptr<T>(p: *mut T) {
+    if mem::size_of::<T>() == 0 {
+        // the sentinel, nothing to do.
+    } else if is_overaligned::<T>() {
+        // aligned allocations may point into the middle of the
+        // underlying block.
+        ffi::GC_free(ffi::GC_base(p as *mut libc::c_void))
+    } else if debug_alloc() {
+        ffi::GC_debug_free(p as *mut libc::c_void)
+    } else {
+        ffi::GC_free(p as *mut libc::c_void)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+    use libc;
+    use ffi;
+    use testing;
+    use tracing::{GcTracing, BoehmTraced};
+    use super::{Gc, GcBox, GcPinned};
+
+    // there's no way to ask for an alignment directly, but SIMD
+    // vectors are aligned to their size.
+    #[simd]
+    #[allow(non_camel_case_types)]
+    struct f32x4(f32, f32, f32, f32);
+    #[simd]
+    #[allow(non_camel_case_types)]
+    struct u64x4(u64, u64, u64, u64);
+    #[simd]
+    #[allow(non_camel_case_types)]
+    struct u64x8(u64, u64, u64, u64, u64, u64, u64, u64);
+
+    impl BoehmTraced for f32x4 {
+        fn indicate_ptr_words(_: Option<f32x4>, _: &mut [bool]) {}
+    }
+    impl BoehmTraced for u64x4 {
+        fn indicate_ptr_words(_: Option<u64x4>, _: &mut [bool]) {}
+    }
+    impl BoehmTraced for u64x8 {
+        fn indicate_ptr_words(_: Option<u64x8>, _: &mut [bool]) {}
+    }
+
+    struct Pair {
+        first: uint,
+        second: uint,
+    }
+
+    #[inline(never)]
+    fn project_second() -> (super::GcRef<uint>, super::GcWeak<Pair>) {
+        let pair = Gc::new(Pair { first: 1, second: 2 });
+        (pair.project(|p| &p.second), pair.downgrade())
+    }
+
+    #[test]
+    fn projection_keeps_parent_alive() {
+        testing::run(|| {
+            let (second, pair) = project_second();
+            testing::collect_fully();
+            assert!(pair.is_alive());
+            assert_eq!(*second.borrow(), 2);
+            assert_eq!(pair.upgrade().unwrap().borrow().first, 1);
+        })
+    }
+
+    struct Big {
+        data: [uint, .. 1024],
+        last: uint,
+    }
+
+    // only pointers near the start of a large object are recognised,
+    // so this would be collected if the `GcRef` didn't keep the
+    // parent's pointer.
+    #[inline(never)]
+    fn project_into_large() -> (super::GcRef<uint>, super::GcWeak<Big>) {
+        let big = Gc::new_large(Big { data: [0, .. 1024], last: 3 });
+        (big.project(|b| &b.last), big.downgrade())
+    }
+
+    #[test]
+    fn projection_keeps_large_parent_alive() {
+        testing::run(|| {
+            let (last, big) = project_into_large();
+            testing::collect_fully();
+            assert!(big.is_alive());
+            assert_eq!(*last.borrow(), 3);
+            assert_eq!(big.upgrade().unwrap().borrow().data[1023], 0);
+        })
+    }
+
+    #[inline(never)]
+    fn pin_referent() -> (GcPinned<Gc<uint>>, super::GcWeak<uint>) {
+        let gc = Gc::new(5u);
+        (GcPinned::new(gc.clone()), gc.downgrade())
+    }
+
+    #[inline(never)]
+    fn read_pinned(pinned: &GcPinned<Gc<uint>>) -> uint {
+        *pinned.borrow().borrow()
+    }
+
+    #[test]
+    fn pinned_value_is_a_root() {
+        testing::run(|| {
+            let (pinned, weak) = pin_referent();
+            testing::collect_fully();
+            assert!(weak.is_alive());
+            assert_eq!(read_pinned(&pinned), 5);
+
+            drop(pinned);
+            testing::collect_fully();
+            assert!(!weak.is_alive());
+        })
+    }
+
+    #[test]
+    #[should_fail]
+    fn projection_outside_object() {
+        testing::run(|| {
+            let pair = Gc::new(Pair { first: 1, second: 2 });
+            let other = 3u;
+            pair.project(|_| &other);
+        })
+    }
+
+    struct Empty;
+    impl BoehmTraced for Empty {
+        fn indicate_ptr_words(_: Option<Empty>, _: &mut [bool]) {}
+    }
+    impl BoehmTraced for [u8, .. 3] {
+        fn indicate_ptr_words(_: Option<[u8, .. 3]>, _: &mut [bool]) {}
+    }
+
+    static mut DROPS: uint = 0;
+    struct CountDrops;
+    impl Drop for CountDrops {
+        fn drop(&mut self) {
+            unsafe { DROPS += 1; }
+        }
+    }
+
+    #[test]
+    fn zero_sized() {
+        testing::run(|| unsafe {
+            let unit = Gc::new(());
+            assert_eq!(unit.allocation_size(), 0);
+            assert!(!(unit.borrow() as *const ()).is_null());
+            assert_eq!(*GcTracing::new(()).borrow(), ());
+            Gc::free(unit);
+
+            let empty = Gc::new(Empty);
+            assert_eq!(empty.allocation_size(), 0);
+            let _ = GcTracing::new(Empty);
+            Gc::free(empty);
+
+            DROPS = 0;
+            drop(GcBox::new(CountDrops));
+            assert_eq!(DROPS, 1);
+            GcBox::new(CountDrops).into_inner();
+            assert_eq!(DROPS, 2);
+            drop(GcPinned::new(CountDrops));
+            assert_eq!(DROPS, 3);
+        })
+    }
+
+    #[test]
+    fn smaller_than_a_word() {
+        testing::run(|| unsafe {
+            let bytes = Gc::new([1u8, 2, 3]);
+            assert!(bytes.allocation_size() >= 3);
+            assert_eq!(bytes.borrow().as_slice(), [1u8, 2, 3].as_slice());
+            Gc::free(bytes);
+
+            let traced = GcTracing::new([4u8, 5, 6]);
+            assert_eq!(traced.borrow().as_slice(), [4u8, 5, 6].as_slice());
+            assert!(BoehmTraced::is_pointer_free(None::<[u8, .. 3]>));
+
+            let mut boxed = GcBox::new([7u8, 8, 9]);
+            boxed.borrow_mut()[0] = 10;
+            assert_eq!(boxed.into_inner().as_slice(), [10u8, 8, 9].as_slice());
+            drop(GcBox::new([0u8, 0, 0]));
+        })
+    }
+
+    fn is_aligned<T>(p: *const T) -> bool {
+        p as uint % mem::min_align_of::<T>() == 0
+    }
+
+    fn check_alignment<T: BoehmTraced + 'static>(make: || -> T, align: uint) {
+        assert_eq!(mem::min_align_of::<T>(), align);
+        testing::run(|| {
+            assert!(is_aligned(Gc::new(make()).borrow() as *const T));
+            assert!(is_aligned(Gc::new_large(make()).borrow() as *const T));
+            assert!(is_aligned(GcTracing::new(make()).borrow() as *const T));
+            assert!(is_aligned(GcTracing::new_large(make()).borrow() as *const T));
+            assert!(is_aligned(GcPinned::new(make()).as_ptr() as *const T));
+            assert!(is_aligned(unsafe { GcPinned::new_atomic(make()).as_ptr() } as *const T));
+            assert!(is_aligned(GcBox::new(make()).borrow() as *const T));
+        })
+    }
+
+    #[test]
+    fn align_16() {
+        check_alignment(|| f32x4(1.0, 2.0, 3.0, 4.0), 16)
+    }
+
+    #[test]
+    fn align_32() {
+        check_alignment(|| u64x4(1, 2, 3, 4), 32)
+    }
+
+    #[test]
+    fn align_64() {
+        check_alignment(|| u64x8(1, 2, 3, 4, 5, 6, 7, 8), 64)
+    }
+
+    // a page-aligned type would need a 512-lane SIMD vector, so check
+    // the allocation paths that over-aligned types use directly.
+    #[test]
+    fn align_4096() {
+        testing::run(|| unsafe {
+            let align = 4096;
+            for &size in [1u, 100, 4096, 10000].iter() {
+                let p = super::memalign_bytes(align, size);
+                assert!(!p.is_null());
+                assert_eq!(p as uint % align, 0);
+
+                let raw = ffi::GC_malloc_uncollectable((size + align - 1) as libc::size_t);
+                let q = super::align_up_bytes(raw, align);
+                assert_eq!(q as uint % align, 0);
+                assert!(q as uint - raw as uint < align);
+                ffi::GC_free(raw);
+            }
+        })
+    }
+
+    #[test]
+    fn no_gc_guard_prevents_collection() {
+        testing::run(|| {
+            let before = super::gc_count();
+            {
+                let outer = super::disable();
+                assert!(outer.is_disabled());
+                super::collect();
+                {
+                    let _inner = super::disable();
+                    super::collect();
+                }
+                // still disabled by `outer`
+                assert!(super::is_disabled());
+                super::collect();
+                assert_eq!(super::gc_count(), before);
+            }
+            assert!(!super::is_disabled());
+            super::collect();
+            assert!(super::gc_count() > before);
+        })
+    }
 }
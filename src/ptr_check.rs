@@ -0,0 +1,348 @@
+//! Queries about pointers into the GC heap, for debugging.
+//!
+//! Boehm's checking functions report failures by calling a "print
+//! proc" that aborts by default; these wrappers install procs that
+//! just record the failure, and return it as a `PointerError`
+//! instead. Failures of checks made elsewhere (e.g. from C) are passed
+//! on to whatever procs were installed before.
+
+use libc;
+use std::{mem, raw};
+use std::sync::{Once, ONCE_INIT};
+use std::sync::atomics::{AtomicBool, AtomicUint, INIT_ATOMIC_BOOL, INIT_ATOMIC_UINT, SeqCst};
+
+use ffi;
+use warn;
+
+/// The ways in which a pointer can fail a check.
+#[deriving(Clone, PartialEq, Show)]
+pub enum PointerError {
+    /// The pointer doesn't point into an object in the GC heap.
+    NotHeapPointer,
+    /// The two pointers point into different objects.
+    DifferentObjects,
+    /// The pointer is stored somewhere the collector doesn't scan.
+    NotVisible,
+}
+
+/// Whether `ptr` points into an object in the GC heap.
+///
+/// A pointer one past the end of an object doesn't count: it is
+/// either not a heap pointer at all, or (if another object follows)
+/// a pointer to the start of the next object.
+pub fn is_heap_ptr<T>(ptr: *const T) -> bool {
+    base(ptr).is_some()
+}
+
+/// The start of the heap object that `ptr` points into, or `None` if
+/// it doesn't point into the GC heap.
+pub fn base<T>(ptr: *const T) -> Option<*const u8> {
+    let b = unsafe { ffi::GC_base(ptr as *mut libc::c_void) };
+    if b.is_null() { None } else { Some(b as *const u8) }
+}
+
+/// The size (in bytes) of the heap object that `ptr` points into.
+/// This is the size of the whole underlying allocation, so it may be
+/// more than was requested.
+pub fn allocation_size<T>(ptr: *const T) -> Result<uint, PointerError> {
+    match base(ptr) {
+        Some(b) => Ok(unsafe { ffi::GC_size(b as *const libc::c_void) as uint }),
+        None => Err(NotHeapPointer)
+    }
+}
+
+/// Check that `a` and `b` point into the same heap object (or are both
+/// outside the heap), e.g. after pointer arithmetic.
+pub fn same_object<T, U>(a: *const T, b: *const U) -> Result<(), PointerError> {
+    if checking(|| unsafe {
+        ffi::GC_same_obj(a as *mut libc::c_void, b as *mut libc::c_void);
+    }) {
+        Err(DifferentObjects)
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that the location `ptr` is visible to the collector, i.e.
+/// that a GC pointer stored there would keep its referent alive.
+pub fn is_visible<T>(ptr: *const T) -> Result<(), PointerError> {
+    if checking(|| unsafe { ffi::GC_is_visible(ptr as *mut libc::c_void); }) {
+        Err(NotVisible)
+    } else {
+        Ok(())
+    }
+}
+
+// whether a check has failed, inside `checking` on this thread (and
+// `None` outside of it).
+local_data_key!(CHECK_FAILED: bool)
+
+type PrintProc1 = extern "C" fn(p: *mut libc::c_void);
+type PrintProc2 = extern "C" fn(p: *mut libc::c_void, q: *mut libc::c_void);
+
+// the print procs that were installed before ours, which are still
+// called for failures outside of `checking`.
+static mut PREV_SAME_OBJ: Option<PrintProc2> = None;
+static mut PREV_DISPLACEMENT: Option<PrintProc1> = None;
+static mut PREV_VISIBLE: Option<PrintProc1> = None;
+
+/// Run `f` (which calls some of Boehm's checking functions), and
+/// return whether any of the checks failed.
+pub fn checking(f: ||) -> bool {
+    static mut INSTALL: Once = ONCE_INIT;
+    unsafe {
+        INSTALL.doit(|| {
+            PREV_SAME_OBJ = Some(ffi::GC_same_obj_print_proc);
+            PREV_DISPLACEMENT = Some(ffi::GC_is_valid_displacement_print_proc);
+            PREV_VISIBLE = Some(ffi::GC_is_visible_print_proc);
+            ffi::GC_same_obj_print_proc = same_obj_failed;
+            ffi::GC_is_valid_displacement_print_proc = displacement_failed;
+            ffi::GC_is_visible_print_proc = visible_failed;
+        });
+    }
+
+    let outer = CHECK_FAILED.replace(Some(false));
+    f();
+    CHECK_FAILED.replace(outer).unwrap_or(false)
+}
+
+/// Record a failure if inside `checking`, returning whether it was.
+fn record_failure() -> bool {
+    if CHECK_FAILED.get().is_some() {
+        CHECK_FAILED.replace(Some(true));
+        true
+    } else {
+        false
+    }
+}
+
+extern "C" fn same_obj_failed(p: *mut libc::c_void, q: *mut libc::c_void) {
+    if !record_failure() {
+        match unsafe { PREV_SAME_OBJ } {
+            Some(prev) => prev(p, q),
+            None => {}
+        }
+    }
+}
+extern "C" fn displacement_failed(p: *mut libc::c_void) {
+    if !record_failure() {
+        match unsafe { PREV_DISPLACEMENT } {
+            Some(prev) => prev(p),
+            None => {}
+        }
+    }
+}
+extern "C" fn visible_failed(p: *mut libc::c_void) {
+    if !record_failure() {
+        match unsafe { PREV_VISIBLE } {
+            Some(prev) => prev(p),
+            None => {}
+        }
+    }
+}
+
+// the file names that have been passed to the debug allocator. The
+// allocator stores a pointer to the name and the line number in each
+// object's header; this is what lets `allocation_site` recognise
+// them. It is append-only, and bounded by the number of distinct
+// files (rather than objects), so it's read without locking.
+static mut FILES: [AtomicUint, .. 64] = [
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+];
+
+// whether `record_file` has run out of room in `FILES` (and warned
+// about it).
+static mut FILES_FULL: AtomicBool = INIT_ATOMIC_BOOL;
+
+/// The debug allocator's header is a handful of words; the file name
+/// is looked for within this many words before the object.
+static MAX_HEADER_WORDS: uint = 8;
+
+/// The file and line that allocated the object at `ptr`, as passed to
+/// `Gc::new_at` (usually by `gc_new!`).
+///
+/// This is read from the header that the debug allocator (the `debug`
+/// feature) puts before each object, so it's only available for
+/// objects allocated by it, and `ptr` must be the pointer returned by
+/// the allocation, not a pointer into the object.
+///
+/// Only the first 64 distinct file names are remembered (the same
+/// file can count more than once, if its name is in several crates),
+/// so objects allocated in any others have no known site. A warning
+/// is given when that first happens.
+pub fn allocation_site<T>(ptr: *const T) -> Option<(&'static str, uint)> {
+    let base = match base(ptr) {
+        Some(b) => b as uint,
+        None => return None
+    };
+    let (p, word) = (ptr as uint, mem::size_of::<ffi::GC_word>());
+    if p <= base || p - base > MAX_HEADER_WORDS * word {
+        return None
+    }
+
+    // the header holds the file name, immediately followed by the line.
+    let mut addr = base;
+    while addr + 2 * word <= p {
+        let file = unsafe { *(addr as *const uint) };
+        if is_known_file(file) {
+            let line = unsafe { *((addr + word) as *const uint) };
+            return if line == 0 {
+                None
+            } else {
+                Some((unsafe { file_name(file) }, line))
+            }
+        }
+        addr += word;
+    }
+    None
+}
+
+/// Remember `file` (a nul-terminated allocation site file name) so
+/// that `allocation_site` can find it in debug headers. Files beyond
+/// the first 64 aren't remembered, so their sites are unknown.
+pub fn record_file(file: &'static str) {
+    let addr = file.as_ptr() as uint;
+    unsafe {
+        for slot in FILES.iter() {
+            let current = slot.load(SeqCst);
+            if current == addr {
+                return
+            }
+            if current == 0 {
+                let prev = slot.compare_and_swap(0, addr, SeqCst);
+                if prev == 0 || prev == addr {
+                    return
+                }
+                // another thread took this slot for a different file.
+            }
+        }
+
+        if !FILES_FULL.swap(true, SeqCst) {
+            warn::warn(format!("allocation sites in more than {} files can't be recorded, \
+                                so those in {} (and later ones) are unknown",
+                               FILES.len(), file.slice_to(file.len() - 1)).as_slice());
+        }
+    }
+}
+
+fn is_known_file(addr: uint) -> bool {
+    if addr == 0 {
+        return false
+    }
+    unsafe {
+        for slot in FILES.iter() {
+            match slot.load(SeqCst) {
+                0 => return false,
+                a if a == addr => return true,
+                _ => {}
+            }
+        }
+    }
+    false
+}
+
+/// The name (without the nul terminator) of a file recorded by
+/// `record_file`, from its address.
+unsafe fn file_name(addr: uint) -> &'static str {
+    let len = libc::strlen(addr as *const libc::c_char) as uint;
+    mem::transmute(raw::Slice { data: addr as *const u8, len: len })
+}
+
+#[cfg(test)]
+mod test {
+    use libc;
+    use std::mem;
+    use ffi;
+    use testing;
+    use Gc;
+    use super::{allocation_site, base, allocation_size, same_object, is_visible};
+    use super::{NotHeapPointer, DifferentObjects, NotVisible};
+
+    struct Pair {
+        first: uint,
+        second: uint,
+    }
+
+    #[test]
+    fn base_and_size() {
+        testing::run(|| {
+            let pair = Gc::new(Pair { first: 1, second: 2 });
+            let start = pair.borrow() as *const Pair;
+            let b = base(start).unwrap();
+            // debug allocations have a header before the object.
+            assert!(b as uint <= start as uint);
+            assert!(allocation_size(start).unwrap() >= mem::size_of::<Pair>());
+
+            let second = &pair.borrow().second as *const uint;
+            assert_eq!(base(second), Some(b));
+            assert_eq!(allocation_size(second), allocation_size(start));
+
+            let local = 3u;
+            assert_eq!(base(&local as *const uint), None);
+            assert_eq!(allocation_size(&local as *const uint), Err(NotHeapPointer));
+        })
+    }
+
+    #[test]
+    fn same_object_checks() {
+        testing::run(|| {
+            let a = Gc::new(Pair { first: 1, second: 2 });
+            let b = Gc::new(Pair { first: 3, second: 4 });
+            assert_eq!(same_object(&a.borrow().first as *const uint,
+                                   &a.borrow().second as *const uint), Ok(()));
+            assert_eq!(same_object(a.borrow() as *const Pair, b.borrow() as *const Pair),
+                       Err(DifferentObjects));
+            // a failed check doesn't affect the next one.
+            assert_eq!(same_object(b.borrow() as *const Pair,
+                                   &b.borrow().second as *const uint), Ok(()));
+        })
+    }
+
+    #[test]
+    fn visibility() {
+        testing::run(|| unsafe {
+            let slot = 0u;
+            assert_eq!(is_visible(&slot as *const uint), Ok(()));
+
+            // memory that has been handed back to the collector isn't
+            // scanned.
+            let block = ffi::GC_malloc(64 * 1024) as *mut u8;
+            ffi::GC_free(block as *mut libc::c_void);
+            assert_eq!(is_visible(block.offset(1024) as *const u8), Err(NotVisible));
+        })
+    }
+
+    #[test]
+    #[cfg(feature = "debug")]
+    fn allocation_site_from_header() {
+        testing::run(|| {
+            let x = Gc::new_at(1u, "some/file.rs\x00", 42);
+            assert_eq!(allocation_site(x.borrow() as *const uint), Some(("some/file.rs", 42)));
+            let y = Gc::new(2u);
+            assert_eq!(allocation_site(y.borrow() as *const uint), None);
+        })
+    }
+
+    #[test]
+    fn no_site_outside_heap() {
+        testing::run(|| {
+            let x = 1u;
+            assert_eq!(allocation_site(&x as *const uint), None);
+        })
+    }
+}
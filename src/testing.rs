@@ -0,0 +1,62 @@
+//! Helpers for the crate's own tests.
+
+use std::intrinsics;
+use std::ptr;
+use std::rt::mutex::{StaticNativeMutex, NATIVE_MUTEX_INIT};
+
+use ffi;
+
+static mut LOCK: StaticNativeMutex = NATIVE_MUTEX_INIT;
+
+/// Unregisters the current thread from the collector when dropped, so
+/// that a failing test doesn't leave a dead thread behind for the
+/// collector to try to stop.
+struct Registration;
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        unsafe { ffi::GC_unregister_my_thread(); }
+    }
+}
+
+/// Run `f` as a test of the collector.
+///
+/// Tests share the collector's global state (the collection count,
+/// whether collection is disabled, ...), so they are run one at a
+/// time. The test harness runs each test on its own thread, which is
+/// registered with the collector while `f` runs so that its stack is
+/// scanned.
+pub fn run(f: ||) {
+    unsafe {
+        let _guard = LOCK.lock();
+        ::init();
+        ffi::GC_allow_register_threads();
+        let mut sb = ffi::Struct_GC_stack_base { mem_base: ptr::null_mut() };
+        ffi::GC_get_stack_base(&mut sb);
+        ffi::GC_register_my_thread(&sb as *const ffi::Struct_GC_stack_base);
+        let _registration = Registration;
+        f();
+    }
+}
+
+/// Overwrite a chunk of the stack below the caller, so that stale
+/// copies of pointers left there by earlier calls don't keep objects
+/// alive when checking that they have been collected.
+#[inline(never)]
+pub fn clear_stack() {
+    let mut buf = [0u, .. 1024];
+    for x in buf.mut_iter() {
+        unsafe { intrinsics::volatile_store(x as *mut uint, 0) }
+    }
+}
+
+/// Collect (and run any finalizers that became ready) a few times,
+/// enough for objects kept alive only by finalizable objects to be
+/// reclaimed.
+pub fn collect_fully() {
+    for _ in range(0u, 3) {
+        clear_stack();
+        ::collect();
+        ::run_finalizers();
+    }
+}
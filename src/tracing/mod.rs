@@ -9,7 +9,7 @@ use libc;
 
 use ffi;
 use ffi::GC_word;
-use std::{mem};
+use std::{mem, ptr};
 use std::kinds::marker;
 use std::intrinsics;
 
@@ -29,6 +29,13 @@ fn GC_set_bit(bm: &mut [GC_word], index: uint) {
 }
 fn GC_WORD_LEN<T>() -> uint { mem::size_of::<T>() / mem::size_of::<GC_word>() }
 
+/// The number of words that a `T` (partially) occupies. Trailing bytes
+/// that don't fill a whole word still count as a word, they just can
+/// never be marked as a pointer.
+fn num_words<T>() -> uint {
+    (mem::size_of::<T>() * 8 + GC_WORDSZ() - 1) / GC_WORDSZ()
+}
+
 fn GC_BITMAP_SIZE<T>() -> uint { (GC_WORD_LEN::<T>() + GC_WORDSZ() - 1) / GC_WORDSZ() }
 
 /// Construct a tracing descriptor out of the `bitmap`, which should
@@ -75,26 +82,79 @@ impl<T: BoehmTraced> GcTracing<T> {
     /// allocation, and so is quite slow.
     ///
     /// TODO: fix that (requires compiler hooks)
+    ///
+    /// Boehm has no aligned allocator for typed objects, so values
+    /// that need more than its default alignment (two words) are
+    /// allocated with `GC_memalign` and are scanned conservatively.
     pub fn new(value: T) -> GcTracing<T> {
-        unsafe {
-            let size = mem::size_of::<T>() as libc::size_t;
+        GcTracing::new_at(value, "GcTracing\x00", 0)
+    }
 
-            let p = if cfg!(debug) {
-                ffi::GC_debug_malloc(size, b"GcTracing\x00".as_ptr() as *const i8, 0)
+    /// Create a new GcTracing, with an allocation site for the debug
+    /// allocator (see `Gc::new_at` and `gc_tracing_new!`).
+    pub fn new_at(value: T, file: &'static str, line: uint) -> GcTracing<T> {
+        let size = mem::size_of::<T>();
+        if size >= ::large_object_threshold() {
+            return GcTracing::new_large_at(value, file, line)
+        }
+        unsafe {
+            let size = size as libc::size_t;
+
+            let site = if ::debug_alloc() { ::debug_site(file, line) } else { ptr::null() };
+            let p = if mem::size_of::<T>() == 0 {
+                ::zst_sentinel::<T>()
+            } else if ::is_overaligned::<T>() {
+                ::memalign::<T>()
+            } else if ::debug_alloc() {
+                ffi::GC_debug_malloc(size, site, line as libc::c_int)
             } else {
                 ffi::GC_malloc_explicitly_typed(size,
                                                 BoehmTraced::get_tracing_descr(None::<T>))
-            } as *mut T;
+            };
+            GcTracing::init(p as *mut T, value)
+        }
+    }
 
-            if p.is_null() {
-                fail!("Could not allocate")
-            }
-            intrinsics::move_val_init(&mut *p, value);
-            GcTracing {
-                ptr: p,
-                mark: marker::NoSend
-                //force_managed: None
-            }
+    /// Create a new GcTracing for a large value, which only needs
+    /// pointers to the start of the allocation to be recognised (see
+    /// `Gc::new_large`). If `T` contains no pointers at all, the
+    /// allocation isn't scanned.
+    pub fn new_large(value: T) -> GcTracing<T> {
+        GcTracing::new_large_at(value, "GcTracing\x00", 0)
+    }
+
+    /// `new_large`, with an allocation site as for `new_at`.
+    pub fn new_large_at(value: T, file: &'static str, line: uint) -> GcTracing<T> {
+        unsafe {
+            let size = mem::size_of::<T>() as libc::size_t;
+
+            let site = if ::debug_alloc() { ::debug_site(file, line) } else { ptr::null() };
+            let p = if mem::size_of::<T>() == 0 {
+                ::zst_sentinel::<T>()
+            } else if ::is_overaligned::<T>() {
+                ::memalign::<T>()
+            } else if ::debug_alloc() {
+                ffi::GC_debug_malloc_ignore_off_page(size, site,
+                                                     line as libc::c_int)
+            } else if BoehmTraced::is_pointer_free(None::<T>) {
+                ffi::GC_malloc_atomic_ignore_off_page(size)
+            } else {
+                ffi::GC_malloc_explicitly_typed_ignore_off_page(
+                    size, BoehmTraced::get_tracing_descr(None::<T>))
+            };
+            GcTracing::init(p as *mut T, value)
+        }
+    }
+
+    unsafe fn init(p: *mut T, value: T) -> GcTracing<T> {
+        if p.is_null() {
+            fail!("Could not allocate")
+        }
+        intrinsics::move_val_init(&mut *p, value);
+        GcTracing {
+            ptr: p,
+            mark: marker::NoSend
+            //force_managed: None
         }
     }
 
@@ -118,9 +178,7 @@ pub trait BoehmTraced {
     /// Construct the `GC_descr` of `Self`. This should not be
     /// overriden.
     fn get_tracing_descr(dummy: Option<Self>) -> ffi::GC_descr {
-        let sz = mem::size_of::<Self>() * 8;
-        let wrd_sz = GC_WORDSZ();
-        let num_words = sz / wrd_sz;
+        let num_words = num_words::<Self>();
 
         if num_words < 16 {
             let mut vec = [false, .. 16];
@@ -133,6 +191,16 @@ pub trait BoehmTraced {
         }
     }
 
+    /// Whether `Self` contains no words that could be GC pointers, so
+    /// that it can be allocated in memory that is never scanned. This
+    /// should not be overriden.
+    fn is_pointer_free(dummy: Option<Self>) -> bool {
+        let num_words = num_words::<Self>();
+        let mut vec = Vec::from_elem(num_words, false);
+        BoehmTraced::indicate_ptr_words(dummy, vec.as_mut_slice());
+        !vec.iter().any(|&is_ptr| is_ptr)
+    }
+
     /// Mark which words within `Self` can possibly hold relevant
     /// pointers (do *not* explicitly mark which words are not
     /// pointers).
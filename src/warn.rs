@@ -0,0 +1,231 @@
+//! Handling the warnings that the collector emits.
+
+use libc;
+use std::c_str::CString;
+use std::fmt::radix;
+use std::mem;
+use std::rt::mutex::{StaticNativeMutex, NATIVE_MUTEX_INIT};
+use std::rt::unwind;
+
+use ffi;
+use finalize;
+
+/// A closure that is called with each warning from the collector
+/// (with the `printf`-style placeholder filled in), and the raw value
+/// of its argument.
+///
+/// It may be called from any thread (though never from two at once),
+/// from inside the collector, so it mustn't allocate GC memory.
+pub type WarnHandler = Box<Fn(&str, uint) + Send>;
+
+// the current handler (or null), protected by `HANDLER_LOCK`, which
+// also serialises calls to it.
+static mut HANDLER_LOCK: StaticNativeMutex = NATIVE_MUTEX_INIT;
+static mut WARN_HANDLER: *mut WarnHandler = 0 as *mut WarnHandler;
+static mut IGNORING: bool = false;
+
+/// Send the collector's warnings (such as "Repeated allocation of
+/// very large block") to `handler`, rather than to stderr, e.g.
+///
+/// ```rust,ignore
+/// let (tx, rx) = channel();
+/// boehm::set_warn_handler(box move |&: msg: &str, _arg: uint| {
+///     tx.send(msg.to_string())
+/// });
+/// ```
+pub fn set_warn_handler(handler: WarnHandler) {
+    unsafe {
+        replace_handler(mem::transmute(box handler), false);
+        ffi::GC_set_warn_proc(warn_proc);
+    }
+}
+
+/// Remove the handler installed by `set_warn_handler` (or stop
+/// ignoring warnings), going back to printing them to stderr.
+pub fn clear_warn_handler() {
+    unsafe {
+        replace_handler(0 as *mut WarnHandler, false);
+        ffi::GC_set_warn_proc(warn_proc);
+    }
+}
+
+/// Silently discard all of the collector's warnings (and this
+/// crate's).
+pub fn ignore_warnings() {
+    unsafe {
+        replace_handler(0 as *mut WarnHandler, true);
+        ffi::GC_set_warn_proc(ignore_warn_proc);
+    }
+}
+
+unsafe fn replace_handler(handler: *mut WarnHandler, ignoring: bool) {
+    let old = {
+        let _guard = HANDLER_LOCK.lock();
+        let old = WARN_HANDLER;
+        WARN_HANDLER = handler;
+        IGNORING = ignoring;
+        old
+    };
+    if !old.is_null() {
+        let _: Box<WarnHandler> = mem::transmute(old);
+    }
+}
+
+/// Log the collector's warnings with `warn!`, which `init` sets up
+/// when the `log` feature is enabled (if no other handler has been
+/// set).
+#[cfg(feature = "log")]
+pub fn log_warning(msg: &str, _arg: uint) {
+    warn!("{}", msg)
+}
+
+/// Whether a handler has been set (or warnings are being ignored),
+/// which `init` leaves alone.
+pub fn handler_set() -> bool {
+    unsafe {
+        let _guard = HANDLER_LOCK.lock();
+        !WARN_HANDLER.is_null() || IGNORING
+    }
+}
+
+/// Report a problem found by this crate (rather than the collector)
+/// through the same handler as the collector's warnings, or to stderr
+/// if there isn't one (unless warnings are being ignored).
+pub fn warn(msg: &str) {
+    report(msg, 0)
+}
+
+/// Pass a warning to the handler, or print it if there isn't one.
+///
+/// Warnings are often reported from inside the collector (or a
+/// finalizer), which failure mustn't unwind through, so a failing
+/// handler is stopped here (as a failing finalizer is).
+fn report(msg: &str, arg: uint) {
+    unsafe {
+        let _guard = HANDLER_LOCK.lock();
+        if IGNORING {
+            return
+        }
+        if WARN_HANDLER.is_null() {
+            let _ = writeln!(::std::io::stderr(), "GC Warning: {}", msg);
+            return
+        }
+        let handler = WARN_HANDLER;
+        if unwind::try(|| (*handler).call((msg, arg))).is_err() {
+            finalize::reset_unwinding();
+        }
+    }
+}
+
+extern "C" fn warn_proc(msg: *mut libc::c_schar, arg: ffi::GC_word) {
+    let msg = unsafe { CString::new(msg as *const libc::c_char, false) };
+    let text = format_warning(msg.as_str().unwrap_or(""), arg as uint);
+    report(text.as_slice(), arg as uint);
+}
+
+// the same as `GC_ignore_warn_proc`, but usable as a Rust function
+// pointer.
+extern "C" fn ignore_warn_proc(_msg: *mut libc::c_schar, _arg: ffi::GC_word) {}
+
+/// Substitute `arg` into the (single) `printf` conversion in `msg`,
+/// and strip the trailing newline.
+fn format_warning(msg: &str, arg: uint) -> String {
+    let mut out = String::with_capacity(msg.len() + 16);
+    let mut chars = msg.chars();
+    let mut substituted = false;
+    loop {
+        let c = match chars.next() {
+            Some(c) => c,
+            None => break
+        };
+        if c != '%' {
+            out.push_char(c);
+            continue
+        }
+
+        // skip flags, width, precision and length modifiers.
+        let mut conv = None;
+        for c in chars.by_ref() {
+            if !"-+ #0123456789.hlzjt".contains_char(c) {
+                conv = Some(c);
+                break
+            }
+        }
+        match conv {
+            Some('%') => out.push_char('%'),
+            Some(_) if substituted => {}
+            Some('d') | Some('i') => {
+                out.push_str((arg as int).to_string().as_slice());
+                substituted = true;
+            }
+            Some('x') | Some('X') => {
+                out.push_str(format!("{}", radix(arg, 16)).as_slice());
+                substituted = true;
+            }
+            Some('p') => {
+                out.push_str(format!("{:#x}", arg).as_slice());
+                substituted = true;
+            }
+            Some(_) => {
+                out.push_str(arg.to_string().as_slice());
+                substituted = true;
+            }
+            None => {}
+        }
+    }
+
+    out.as_slice().trim_right().to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use testing;
+    use super::{format_warning, set_warn_handler, clear_warn_handler, warn};
+
+    #[test]
+    fn handler_captures_channel() {
+        testing::run(|| {
+            let (tx, rx) = channel();
+            set_warn_handler(box move |&: msg: &str, arg: uint| {
+                tx.send((msg.to_string(), arg))
+            });
+            warn("something happened");
+            clear_warn_handler();
+            assert_eq!(rx.recv(), ("something happened".to_string(), 0));
+        })
+    }
+
+    #[test]
+    fn failing_handler_is_stopped() {
+        testing::run(|| {
+            set_warn_handler(box |&: _msg: &str, _arg: uint| fail!("handler failed"));
+            // the second failure would abort if the first had left the
+            // task unwinding.
+            warn("first");
+            warn("second");
+            clear_warn_handler();
+        })
+    }
+
+    #[test]
+    fn format_decimal() {
+        assert_eq!(format_warning("Out of memory (%d)\n", 12).as_slice(),
+                   "Out of memory (12)");
+        assert_eq!(format_warning("Repeated allocation of very large block (appr. size %ld):\n",
+                                  1052672).as_slice(),
+                   "Repeated allocation of very large block (appr. size 1052672):");
+        assert_eq!(format_warning("%ld bytes, %d again", 5).as_slice(), "5 bytes,  again");
+    }
+
+    #[test]
+    fn format_pointer() {
+        assert_eq!(format_warning("Failed to expand heap at %p\n", 0x1000).as_slice(),
+                   "Failed to expand heap at 0x1000");
+    }
+
+    #[test]
+    fn format_percent() {
+        assert_eq!(format_warning("100%% of %lu\n", 7).as_slice(), "100% of 7");
+        assert_eq!(format_warning("no conversions\n", 7).as_slice(), "no conversions");
+    }
+}
@@ -0,0 +1,109 @@
+//! Weak references, using disappearing links.
+
+use libc;
+use std::{mem, ptr};
+use std::kinds::marker;
+
+use ffi;
+use Gc;
+
+/// A reference to a GC object that doesn't keep it alive.
+///
+/// The address of the object is stored (hidden from the collector) in
+/// uncollectable memory that is registered with the collector as a
+/// "disappearing link", which it clears when the object is found to be
+/// unreachable.
+pub struct GcWeak<T> {
+    link: *mut ffi::GC_word,
+    mark: marker::NoSend
+}
+
+impl<T: 'static> GcWeak<T> {
+    /// A weak reference to `gc`.
+    pub fn new(gc: &Gc<T>) -> GcWeak<T> {
+        unsafe { GcWeak::from_raw(gc.ptr) }
+    }
+
+    /// A weak reference to the object at `ptr`, which must have been
+    /// allocated by this crate (or be null, giving a reference that is
+    /// already dead).
+    pub unsafe fn from_raw(ptr: *mut T) -> GcWeak<T> {
+        let link = ffi::GC_malloc_atomic_uncollectable(
+            mem::size_of::<ffi::GC_word>() as libc::size_t) as *mut ffi::GC_word;
+        if link.is_null() {
+            fail!("Could not allocate")
+        }
+
+        if ptr.is_null() {
+            *link = 0;
+        } else {
+            *link = !(ptr as ffi::GC_word);
+            // zero-sized values are never collected, so don't need
+            // the link.
+            if mem::size_of::<T>() != 0 {
+                // the link is registered against the start of the
+                // allocation, since debug and aligned allocations
+                // point into its middle.
+                ffi::GC_general_register_disappearing_link(link as *mut *mut libc::c_void,
+                                                           ffi::GC_base(ptr as *mut libc::c_void));
+            }
+        }
+        GcWeak { link: link, mark: marker::NoSend }
+    }
+
+    /// A strong reference to the object, if it hasn't been collected.
+    pub fn upgrade(&self) -> Option<Gc<T>> {
+        let p = self.as_ptr();
+        if p.is_null() {
+            None
+        } else {
+            Some(Gc { ptr: p, mark: marker::NoSend })
+        }
+    }
+
+    /// Whether the object is still alive.
+    pub fn is_alive(&self) -> bool {
+        !self.as_ptr().is_null()
+    }
+
+    /// The address of the object, or null if it has been collected.
+    ///
+    /// The link is read with the allocation lock held, so that a
+    /// collection can't be clearing it at the same time; once the
+    /// (non-null) address is on the stack, it keeps the object alive.
+    fn as_ptr(&self) -> *mut T {
+        unsafe { read_link(self.link) as *mut T }
+    }
+}
+
+/// Read the hidden pointer stored in the disappearing link `link`,
+/// returning null if it has been cleared. This takes the allocation
+/// lock, so it doesn't race with the collector.
+pub unsafe fn read_link(link: *mut ffi::GC_word) -> *mut libc::c_void {
+    ffi::GC_call_with_alloc_lock(reveal, link as *mut libc::c_void)
+}
+
+extern "C" fn reveal(link: *mut libc::c_void) -> *mut libc::c_void {
+    unsafe {
+        match *(link as *mut ffi::GC_word) {
+            0 => ptr::null_mut(),
+            hidden => (!hidden) as *mut libc::c_void
+        }
+    }
+}
+
+impl<T: 'static> Clone for GcWeak<T> {
+    fn clone(&self) -> GcWeak<T> {
+        unsafe { GcWeak::from_raw(self.as_ptr()) }
+    }
+}
+
+#[unsafe_destructor]
+impl<T> Drop for GcWeak<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::GC_unregister_disappearing_link(self.link as *mut *mut libc::c_void);
+            ffi::GC_free(self.link as *mut libc::c_void);
+        }
+    }
+}
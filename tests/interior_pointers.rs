@@ -0,0 +1,89 @@
+//! Checks that the crate's pointer types keep their objects alive with
+//! interior pointers disabled. This has to be decided before the
+//! collector starts, so it is a separate test binary.
+
+extern crate boehm = "boehm-rs";
+
+use std::{intrinsics, ptr, task};
+use boehm::{ffi, Gc, GcRef, GcCursor, GcWeak, GcConfig};
+
+struct Record {
+    header: [uint, .. 4],
+    value: uint,
+}
+
+// big enough to be allocated as a large object, which only pointers
+// near its start keep alive.
+struct Big {
+    data: [uint, .. 1024],
+    last: uint,
+}
+
+#[inline(never)]
+fn make_projection() -> (GcRef<uint>, GcWeak<Record>) {
+    let record = Gc::new(Record { header: [0, .. 4], value: 1 });
+    (record.project(|r| &r.value), record.downgrade())
+}
+
+#[inline(never)]
+fn make_large_projection() -> (GcRef<uint>, GcWeak<Big>) {
+    let big = Gc::new_large(Big { data: [0, .. 1024], last: 2 });
+    (big.project(|b| &b.last), big.downgrade())
+}
+
+#[inline(never)]
+fn make_cursor() -> (GcCursor<uint>, GcWeak<[uint, .. 16]>) {
+    let buf = Gc::new([3u, .. 16]);
+    let mut cursor = GcCursor::new(&buf);
+    cursor.pre_incr(8);
+    (cursor, buf.downgrade())
+}
+
+// overwrite the stack below, so that stale copies of pointers to the
+// start of the objects don't keep them alive.
+#[inline(never)]
+fn clear_stack() {
+    let mut buf = [0u, .. 1024];
+    for x in buf.mut_iter() {
+        unsafe { intrinsics::volatile_store(x as *mut uint, 0) }
+    }
+}
+
+#[test]
+fn interior_references_keep_objects_alive() {
+    GcConfig::new().interior_pointers(false).init();
+    unsafe {
+        // the test runs on a thread of its own, whose stack needs to be
+        // scanned.
+        ffi::GC_allow_register_threads();
+        let mut sb = ffi::Struct_GC_stack_base { mem_base: ptr::null_mut() };
+        ffi::GC_get_stack_base(&mut sb);
+        ffi::GC_register_my_thread(&sb as *const ffi::Struct_GC_stack_base);
+    }
+    assert_eq!(unsafe { ffi::GC_get_all_interior_pointers() }, 0);
+
+    // each object is only referenced by something pointing into its
+    // middle, which the collector no longer recognises by itself.
+    let (value, record) = make_projection();
+    let (last, big) = make_large_projection();
+    let (cursor, buf) = make_cursor();
+
+    for _ in range(0u, 3) {
+        clear_stack();
+        boehm::collect();
+    }
+
+    assert!(record.is_alive());
+    assert_eq!(*value.borrow(), 1);
+    assert_eq!(record.upgrade().unwrap().borrow().header[0], 0);
+    assert!(big.is_alive());
+    assert_eq!(*last.borrow(), 2);
+    assert!(buf.is_alive());
+    assert_eq!(unsafe { *cursor.get() }, 3);
+
+    // the setting can't be changed on a running collector.
+    assert!(task::try(proc() {
+        GcConfig::new().interior_pointers(true).init();
+    }).is_err());
+    assert_eq!(unsafe { ffi::GC_get_all_interior_pointers() }, 0);
+}
@@ -0,0 +1,50 @@
+//! Runs the leak checker against the real collector. Find-leak mode
+//! has to be chosen before the collector starts, so this is a
+//! separate test binary.
+
+#![feature(phase)]
+
+#[phase(plugin, link)]
+extern crate boehm = "boehm-rs";
+
+use std::intrinsics;
+use boehm::Gc;
+use boehm::leak_check;
+
+// allocate an object and drop it without freeing it, returning the
+// line of the allocation.
+#[inline(never)]
+fn leak() -> uint {
+    let (_x, line) = (gc_new!(1u), line!());
+    line
+}
+
+#[inline(never)]
+fn free() {
+    unsafe { Gc::free(gc_new!(2u)) }
+}
+
+// overwrite the stack below, so that stale copies of the leaked
+// pointer don't keep it reachable.
+#[inline(never)]
+fn clear_stack() {
+    let mut buf = [0u, .. 1024];
+    for x in buf.mut_iter() {
+        unsafe { intrinsics::volatile_store(x as *mut uint, 0) }
+    }
+}
+
+#[test]
+fn finds_exactly_the_leak() {
+    leak_check::enable();
+    boehm::init();
+
+    let line = leak();
+    free();
+    clear_stack();
+
+    let leaks = leak_check::check();
+    assert_eq!(leaks.len(), 1);
+    assert_eq!(leaks[0].file, Some(file!().to_string()));
+    assert_eq!(leaks[0].line, Some(line));
+}